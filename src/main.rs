@@ -1,15 +1,419 @@
-use std::collections::HashMap;
+use itertools::Itertools;
+use std::collections::{HashMap, VecDeque};
 use std::io::{BufRead, BufReader, Read};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{
     io::Write,
-    net::{TcpListener, TcpStream},
+    net::{IpAddr, TcpListener, TcpStream},
 };
 
+/// Writes a log line to `--log-file` when configured, otherwise to stdout.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        LOG.write_line(&format!($($arg)*))
+    };
+}
+
 struct Content {
-    mime_type: &'static str,
-    content: Vec<u8>,
+    mime_type: String,
+    content: Body,
+}
+
+/// An accepted client connection, over TCP or (with `--unix-socket`) a Unix domain socket.
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Connection {
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    fn shutdown_read(&self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(std::net::Shutdown::Read),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(std::net::Shutdown::Read),
+        }
+    }
+
+    /// The client's address; Unix socket peers have none.
+    fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok().map(|addr| addr.ip()),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Reads from a connection, and while a request is being parsed fails the read once the client
+/// has sent less than `--min-read-rate` bytes a second, averaged since the request began and
+/// checked only after `--min-read-rate-window`. This catches slow-loris clients that trickle
+/// bytes in just fast enough to never trip a plain read timeout.
+struct RateCheckedReader<'a> {
+    stream: &'a Connection,
+    measuring_since: Option<Instant>,
+    bytes: usize,
+    too_slow: bool,
+}
+
+impl<'a> RateCheckedReader<'a> {
+    fn new(stream: &'a Connection) -> Self {
+        if CONFIGURATION.min_read_rate.is_some() {
+            // Wake up regularly to check the rate even when the client sends nothing at all.
+            if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(1))) {
+                log!("could not set read timeout: {}", e);
+            }
+        }
+        Self {
+            stream,
+            measuring_since: None,
+            bytes: 0,
+            too_slow: false,
+        }
+    }
+
+    fn start_measuring(&mut self) {
+        self.measuring_since = Some(Instant::now());
+        self.bytes = 0;
+    }
+
+    fn stop_measuring(&mut self) {
+        self.measuring_since = None;
+    }
+
+    fn check_rate(&mut self) -> std::io::Result<()> {
+        let (Some(rate), Some(since)) = (CONFIGURATION.min_read_rate, self.measuring_since) else {
+            return Ok(());
+        };
+        let elapsed = since.elapsed();
+        if elapsed >= CONFIGURATION.min_read_rate_window
+            && (self.bytes as f64) < rate as f64 * elapsed.as_secs_f64()
+        {
+            self.too_slow = true;
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        Ok(())
+    }
+}
+
+impl Read for RateCheckedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let mut stream = self.stream;
+            match stream.read(buf) {
+                Ok(read) => {
+                    self.bytes += read;
+                    self.check_rate()?;
+                    return Ok(read);
+                }
+                // Only the read timeout set for rate checks gets here; between requests the
+                // connection may idle for as long as it likes.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) && CONFIGURATION.min_read_rate.is_some() =>
+                {
+                    self.check_rate()?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A response body, either held in memory or streamed from files when written.
+enum Body {
+    Bytes(Vec<u8>),
+    File {
+        file: std::fs::File,
+        len: usize,
+    },
+    Zip(ZipArchive),
+    /// Server-sent events produced while writing, so the length is not known up front. They
+    /// are sent `chunked`, or unframed until the connection closes when `chunked` is false.
+    Events {
+        count: usize,
+        chunked: bool,
+    },
+}
+
+impl Body {
+    fn len(&self) -> usize {
+        match self {
+            Body::Bytes(bytes) => bytes.len(),
+            Body::File { len, .. } => *len,
+            Body::Zip(archive) => archive.len(),
+            Body::Events { .. } => 0,
+        }
+    }
+
+    fn write_to(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => stream.write_all(bytes),
+            Body::File { file, len } => stream_exactly(&mut &*file, *len, stream, |_| {}),
+            Body::Zip(archive) => archive.write_to(stream),
+            Body::Events { count, chunked } => {
+                for sent in 0..*count {
+                    if sent > 0 {
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_secs());
+                    let event = format!("id: {}\ndata: {}\n\n", sent, iso8601_utc(now));
+                    if *chunked {
+                        write!(stream, "{:x}\r\n{}\r\n", event.len(), event)?;
+                    } else {
+                        stream.write_all(event.as_bytes())?;
+                    }
+                    stream.flush()?;
+                }
+                if *chunked {
+                    stream.write_all(b"0\r\n\r\n")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Copies exactly `len` bytes in `--stream-chunk-bytes` chunks, showing each to `inspect`.
+fn stream_exactly(
+    source: &mut dyn Read,
+    len: usize,
+    stream: &mut dyn Write,
+    mut inspect: impl FnMut(&[u8]),
+) -> std::io::Result<()> {
+    let mut remaining = len;
+    let mut buffer = vec![0; CONFIGURATION.stream_chunk_bytes.min(remaining)];
+    while remaining > 0 {
+        let chunk = buffer.len().min(remaining);
+        let read = source.read(&mut buffer[..chunk])?;
+        if read == 0 {
+            // The file shrank since we announced its length; the response is broken.
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        inspect(&buffer[..read]);
+        stream.write_all(&buffer[..read])?;
+        remaining -= read;
+    }
+    Ok(())
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// An uncompressed ("stored") ZIP of files on disk. Stored entries have a size known up front,
+/// so the whole archive length is too, while each file is only read as it is written out; the
+/// CRCs go in data descriptors after each file's data.
+struct ZipArchive {
+    entries: Vec<ZipEntry>,
+}
+
+struct ZipEntry {
+    name: String,
+    path: PathBuf,
+    len: u32,
+}
+
+impl ZipArchive {
+    const LOCAL_HEADER_LEN: usize = 30;
+    const DESCRIPTOR_LEN: usize = 16;
+    const CENTRAL_HEADER_LEN: usize = 46;
+    const END_LEN: usize = 22;
+    /// Bit 3: sizes and CRC follow in a data descriptor; bit 11: names are UTF-8.
+    const FLAGS: u16 = 0x0808;
+    /// 1980-01-01, the earliest date ZIP can express.
+    const DOS_DATE: u16 = 0x0021;
+
+    /// Collects every regular file below `root`. Fails if any of it cannot be read or does
+    /// not fit the classic ZIP format's 4 GiB and 65535-entry limits.
+    fn of_directory(root: &std::path::Path) -> std::io::Result<Self> {
+        let too_large =
+            || std::io::Error::other(format!("{} does not fit in a ZIP archive", root.display()));
+        let mut entries = Vec::new();
+        let mut pending = vec![(root.to_path_buf(), String::new())];
+        while let Some((dir, prefix)) = pending.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                if metadata.is_dir() {
+                    pending.push((entry.path(), format!("{}/", name)));
+                } else if metadata.is_file() {
+                    entries.push(ZipEntry {
+                        name,
+                        path: entry.path(),
+                        len: u32::try_from(metadata.len()).map_err(|_| too_large())?,
+                    });
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let archive = Self { entries };
+        let fits =
+            archive.entries.len() <= usize::from(u16::MAX) && u32::try_from(archive.len()).is_ok();
+        fits.then_some(archive).ok_or_else(too_large)
+    }
+
+    fn len(&self) -> usize {
+        let entries: usize = self
+            .entries
+            .iter()
+            .map(|entry| {
+                Self::LOCAL_HEADER_LEN
+                    + Self::DESCRIPTOR_LEN
+                    + Self::CENTRAL_HEADER_LEN
+                    + 2 * entry.name.len()
+                    + entry.len as usize
+            })
+            .sum();
+        entries + Self::END_LEN
+    }
+
+    fn write_to(&self, stream: &mut dyn Write) -> std::io::Result<()> {
+        let mut crcs = Vec::with_capacity(self.entries.len());
+        let mut offsets = Vec::with_capacity(self.entries.len());
+        let mut offset = 0;
+        for entry in &self.entries {
+            offsets.push(offset as u32);
+            let mut header = Vec::with_capacity(Self::LOCAL_HEADER_LEN + entry.name.len());
+            header.extend(0x0403_4b50u32.to_le_bytes());
+            for field in [20, Self::FLAGS, 0, 0, Self::DOS_DATE] {
+                header.extend(field.to_le_bytes());
+            }
+            header.extend([0; 12]); // CRC and sizes, given in the data descriptor instead
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            header.extend(0u16.to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            stream.write_all(&header)?;
+
+            let mut crc = 0;
+            let mut file = std::fs::File::open(&entry.path)?;
+            stream_exactly(&mut file, entry.len as usize, stream, |chunk| {
+                crc = crc32_update(crc, chunk)
+            })?;
+            crcs.push(crc);
+
+            let mut descriptor = Vec::with_capacity(Self::DESCRIPTOR_LEN);
+            for field in [0x0807_4b50, crc, entry.len, entry.len] {
+                descriptor.extend(field.to_le_bytes());
+            }
+            stream.write_all(&descriptor)?;
+            offset += header.len() + entry.len as usize + descriptor.len();
+        }
+
+        let central_start = offset;
+        for ((entry, crc), local_offset) in self.entries.iter().zip(crcs).zip(offsets) {
+            let mut header = Vec::with_capacity(Self::CENTRAL_HEADER_LEN + entry.name.len());
+            header.extend(0x0201_4b50u32.to_le_bytes());
+            for field in [20, 20, Self::FLAGS, 0, 0, Self::DOS_DATE] {
+                header.extend(field.to_le_bytes());
+            }
+            for field in [crc, entry.len, entry.len] {
+                header.extend(field.to_le_bytes());
+            }
+            for field in [entry.name.len() as u16, 0, 0, 0, 0] {
+                header.extend(field.to_le_bytes());
+            }
+            header.extend(0u32.to_le_bytes()); // external attributes
+            header.extend(local_offset.to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            stream.write_all(&header)?;
+            offset += header.len();
+        }
+
+        let count = self.entries.len() as u16;
+        let mut end = Vec::with_capacity(Self::END_LEN);
+        end.extend(0x0605_4b50u32.to_le_bytes());
+        for field in [0, 0, count, count] {
+            end.extend(field.to_le_bytes());
+        }
+        end.extend(((offset - central_start) as u32).to_le_bytes());
+        end.extend((central_start as u32).to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        stream.write_all(&end)
+    }
 }
 
 struct StatusCode {
@@ -20,293 +424,6699 @@ struct StatusCode {
 mod status_codes {
     use super::StatusCode;
 
-    const fn status_code(code: u16, status: &'static str) -> StatusCode {
-        StatusCode { code, status }
+    /// Reason phrases as registered with IANA; every status line takes its text from here.
+    pub(super) const fn reason_phrase(code: u16) -> &'static str {
+        match code {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            102 => "Processing",
+            103 => "Early Hints",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            203 => "Non-Authoritative Information",
+            204 => "No Content",
+            205 => "Reset Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            208 => "Already Reported",
+            226 => "IM Used",
+            300 => "Multiple Choices",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            305 => "Use Proxy",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            402 => "Payment Required",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            407 => "Proxy Authentication Required",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            421 => "Misdirected Request",
+            422 => "Unprocessable Content",
+            423 => "Locked",
+            424 => "Failed Dependency",
+            425 => "Too Early",
+            426 => "Upgrade Required",
+            428 => "Precondition Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            451 => "Unavailable For Legal Reasons",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            506 => "Variant Also Negotiates",
+            507 => "Insufficient Storage",
+            508 => "Loop Detected",
+            510 => "Not Extended",
+            511 => "Network Authentication Required",
+            _ => "Unknown",
+        }
+    }
+
+    const fn status_code(code: u16) -> StatusCode {
+        StatusCode {
+            code,
+            status: reason_phrase(code),
+        }
     }
 
-    pub const OK: StatusCode = status_code(200, "OK");
-    pub const CREATED: StatusCode = status_code(201, "Created");
+    pub const OK: StatusCode = status_code(200);
+    pub const CREATED: StatusCode = status_code(201);
+    pub const NO_CONTENT: StatusCode = status_code(204);
+    pub const NOT_MODIFIED: StatusCode = status_code(304);
 
-    pub const NOT_FOUND: StatusCode = status_code(404, "Not Found");
+    pub const BAD_REQUEST: StatusCode = status_code(400);
+    pub const UNAUTHORIZED: StatusCode = status_code(401);
+    pub const FORBIDDEN: StatusCode = status_code(403);
+    pub const NOT_FOUND: StatusCode = status_code(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = status_code(405);
+    pub const REQUEST_TIMEOUT: StatusCode = status_code(408);
+    pub const LENGTH_REQUIRED: StatusCode = status_code(411);
+    pub const PRECONDITION_FAILED: StatusCode = status_code(412);
+    pub const CONTENT_TOO_LARGE: StatusCode = status_code(413);
+    pub const URI_TOO_LONG: StatusCode = status_code(414);
+    pub const UPGRADE_REQUIRED: StatusCode = status_code(426);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = status_code(431);
 
-    pub const INTERNAL_SERVER_ERROR: StatusCode = status_code(500, "Internal Server Error");
+    pub const INTERNAL_SERVER_ERROR: StatusCode = status_code(500);
+    pub const SERVICE_UNAVAILABLE: StatusCode = status_code(503);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = status_code(505);
 }
 
 struct Response<'a> {
     status_code: &'a StatusCode,
     content: Option<Content>,
+    headers: Vec<(String, String)>,
+    /// Ends the connection after this response, whatever the client asked for.
+    close_connection: bool,
+}
+
+/// Counts the bytes that make it through to the underlying writer.
+struct CountingWriter<W> {
+    inner: W,
+    written: usize,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
-fn write_newline(mut stream: &TcpStream) -> std::io::Result<()> {
+fn write_newline(stream: &mut dyn Write) -> std::io::Result<()> {
     stream.write_all(b"\r\n")
 }
 
-fn write_header(mut stream: &TcpStream, key: &str, value: &str) -> std::io::Result<()> {
-    write!(&mut stream, "{}: {}", key, value)?;
+/// Whether a header can be written as is; a CR or LF in it would start a new header line.
+fn is_safe_header(key: &str, value: &str) -> bool {
+    is_token(key) && !value.contains(['\r', '\n'])
+}
+
+/// Writes one header line, dropping (and logging) any header that would inject others.
+fn write_header(stream: &mut dyn Write, key: &str, value: &str) -> std::io::Result<()> {
+    if !is_safe_header(key, value) {
+        log!("dropping unsafe response header {:?}: {:?}", key, value);
+        return Ok(());
+    }
+    write!(stream, "{}: {}", key, value)?;
     write_newline(stream)
 }
 
+/// Whether a response header counts towards `--max-response-headers`; `Connection` and the
+/// headers describing the body always go out.
+fn is_capped_header(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    !(key == "connection" || key == "transfer-encoding" || key.starts_with("content-"))
+}
+
 impl<'a> Response<'a> {
-    fn write_to_stream(&self, mut stream: &TcpStream) -> std::io::Result<()> {
+    /// Writes the response, returning the number of bytes sent. Without `include_body` (for
+    /// HEAD) the headers still describe the body, but its bytes are left out.
+    fn write_to_stream(&self, stream: impl Write, include_body: bool) -> std::io::Result<usize> {
+        let mut stream = CountingWriter::new(stream);
         write!(
             &mut stream,
             "HTTP/1.1 {} {}",
             self.status_code.code, self.status_code.status
         )?;
-        write_newline(stream)?;
+        write_newline(&mut stream)?;
+
+        let defaults = CONFIGURATION
+            .default_headers
+            .iter()
+            .filter(|(key, _)| !self.has_header(key));
+        let mut header_count = 0;
+        let mut header_bytes = 0;
+        let mut truncated = false;
+        for (key, value) in self.headers.iter().chain(defaults) {
+            // Headers that frame the message or manage the connection are never dropped:
+            // without them the client would misread the body or keep a closed connection.
+            if !is_capped_header(key) {
+                write_header(&mut stream, key, value)?;
+                continue;
+            }
+            header_count += 1;
+            header_bytes += key.len() + value.len() + 4;
+            if header_count > CONFIGURATION.max_response_headers
+                || header_bytes > CONFIGURATION.max_response_header_bytes
+            {
+                if !truncated {
+                    log!(
+                        "warning: response headers exceed --max-response-headers {} or \
+                         --max-response-header-bytes {}, dropping {} and the rest",
+                        CONFIGURATION.max_response_headers,
+                        CONFIGURATION.max_response_header_bytes,
+                        key
+                    );
+                    truncated = true;
+                }
+                continue;
+            }
+            write_header(&mut stream, key, value)?;
+        }
 
         if let Some(content) = &self.content {
-            write_header(stream, "Content-Type", content.mime_type)?;
-            write_header(
-                stream,
-                "Content-Length",
-                &format!("{}", content.content.len()),
-            )?;
-            write_newline(stream)?;
-
-            stream.write_all(&content.content)?;
+            let mime_type = if is_safe_header("Content-Type", &content.mime_type) {
+                content.mime_type.as_str()
+            } else {
+                "application/octet-stream"
+            };
+            write_header(&mut stream, "Content-Type", mime_type)?;
+            if let Body::Events { chunked, .. } = content.content {
+                if chunked {
+                    write_header(&mut stream, "Transfer-Encoding", "chunked")?;
+                }
+            } else {
+                write_header(
+                    &mut stream,
+                    "Content-Length",
+                    &format!("{}", content.content.len()),
+                )?;
+            }
+            write_newline(&mut stream)?;
+
+            if include_body {
+                content.content.write_to(&mut stream)?;
+            }
         } else {
-            write_newline(stream)?;
-            write_newline(stream)?;
+            // 1xx, 204 and 304 responses never have a body, so they carry no length either.
+            let code = self.status_code.code;
+            if !(code < 200 || code == 204 || code == 304) {
+                write_header(&mut stream, "Content-Length", "0")?;
+            }
+            write_newline(&mut stream)?;
         }
 
-        Ok(())
+        Ok(stream.written)
     }
 
     fn empty_response(status_code: &'a StatusCode) -> Self {
         Self {
             status_code,
             content: None,
+            headers: Vec::new(),
+            close_connection: false,
         }
     }
 
-    fn text_reponse(status_code: &'a StatusCode, text: &'a str) -> Self {
+    fn content_response(status_code: &'a StatusCode, mime_type: &str, content: Vec<u8>) -> Self {
+        Self::body_response(status_code, mime_type, Body::Bytes(content))
+    }
+
+    fn body_response(status_code: &'a StatusCode, mime_type: &str, content: Body) -> Self {
         Self {
             status_code,
             content: Some(Content {
-                mime_type: "text/plain",
-                content: text.as_bytes().to_vec(),
+                mime_type: mime_type.to_owned(),
+                content,
             }),
+            headers: Vec::new(),
+            close_connection: false,
         }
     }
 
-    fn file_response(path: &PathBuf) -> Self {
-        match std::fs::read(path) {
-            Ok(file_content) => Self {
-                status_code: &status_codes::OK,
-                content: Some(Content {
-                    mime_type: "application/octet-stream",
-                    content: file_content,
-                }),
-            },
-            Err(_) => Self::empty_response(&status_codes::NOT_FOUND),
+    /// Every 503 tells the client when to come back, via `--retry-after`.
+    fn service_unavailable() -> Self {
+        Self::empty_response(&status_codes::SERVICE_UNAVAILABLE).with_header(
+            "Retry-After",
+            &CONFIGURATION.retry_after.as_secs().to_string(),
+        )
+    }
+
+    /// A 500 for a failed operation. The error is always logged, but only `--debug-errors`
+    /// puts it in the body, since it can reveal paths and other details.
+    fn internal_error(error: &dyn std::fmt::Display) -> Self {
+        log!("internal error: {}", error);
+        if CONFIGURATION.debug_errors {
+            Self::content_response(
+                &status_codes::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                error.to_string().into_bytes(),
+            )
+        } else {
+            Self::empty_response(&status_codes::INTERNAL_SERVER_ERROR)
         }
     }
-}
 
-#[derive(Debug)]
-enum Verb {
-    Get,
-    Post,
-}
+    /// The 503 for any request turned away by a concurrency or resource limit, so every
+    /// limiter looks the same to clients. An `--error-page` for 503 replaces the default page.
+    fn server_busy() -> Self {
+        let response = Self::service_unavailable().with_error_page();
+        if response.content.is_some() {
+            return response;
+        }
+        Self {
+            content: Some(Content {
+                mime_type: "text/html; charset=utf-8".to_owned(),
+                content: Body::Bytes(
+                    b"<!DOCTYPE html>\n<p>Server is busy, please retry shortly.</p>\n".to_vec(),
+                ),
+            }),
+            ..response
+        }
+    }
 
-#[derive(Debug)]
-struct Request {
-    verb: Verb,
-    path: String,
-    version: String,
-    headers: HashMap<String, String>,
-    body: Option<Vec<u8>>,
-}
+    fn not_found() -> Self {
+        match &CONFIGURATION.not_found_page {
+            Some(page) => {
+                Self::content_response(&status_codes::NOT_FOUND, "text/html", page.clone())
+            }
+            None => Self::empty_response(&status_codes::NOT_FOUND),
+        }
+    }
 
-#[derive(Debug)]
-enum RequestParseError {
-    InvalidVerb,
-    CouldNotReadStartLine,
-    InvalidStructure,
-    CouldNotReadHeader,
-    InvalidHeader,
-    InvalidContentLength,
-    CouldNotReadBody,
-}
+    /// A body the server produced itself, refused with a 500 once it outgrows
+    /// `--max-generated-bytes`.
+    fn generated_response(status_code: &'a StatusCode, mime_type: &str, content: Vec<u8>) -> Self {
+        match CONFIGURATION.max_generated_bytes {
+            Some(cap) if content.len() > cap => Self::internal_error(&format!(
+                "generated body of {} bytes exceeds --max-generated-bytes {}",
+                content.len(),
+                cap
+            )),
+            _ => Self::content_response(status_code, mime_type, content),
+        }
+    }
 
-fn read_headers(reader: &mut dyn BufRead) -> Result<HashMap<String, String>, RequestParseError> {
-    let mut headers: HashMap<String, String> = HashMap::new();
-    loop {
-        let mut header_line = String::new();
-        reader
-            .read_line(&mut header_line)
-            .map_err(|_| RequestParseError::CouldNotReadHeader)?;
-        let header_line = header_line.trim_end();
-        if header_line.is_empty() {
-            break;
+    fn text_reponse(status_code: &'a StatusCode, text: &str) -> Self {
+        Self::generated_response(status_code, "text/plain", text.as_bytes().to_vec())
+    }
+
+    fn json_response(status_code: &'a StatusCode, json: String) -> Self {
+        Self::generated_response(status_code, "application/json", json.into_bytes())
+    }
+
+    fn file_response(path: &PathBuf) -> Self {
+        let opened = std::fs::File::open(path)
+            .and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+        match opened {
+            Ok((file, metadata)) if metadata.is_file() => {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified);
+                let response = Self {
+                    status_code: &status_codes::OK,
+                    content: Some(Content {
+                        mime_type: "application/octet-stream".to_owned(),
+                        content: Body::File {
+                            file,
+                            len: metadata.len() as usize,
+                        },
+                    }),
+                    headers: Vec::new(),
+                    close_connection: false,
+                }
+                .with_header("ETag", &etag)
+                .with_header("Last-Modified", &http_date(modified));
+                if CONFIGURATION.nosniff {
+                    response.with_header("X-Content-Type-Options", "nosniff")
+                } else {
+                    response
+                }
+            }
+            _ => Self::not_found(),
+        }
+    }
+
+    /// Tags an in-memory body with a weak `ETag` from its CRC-32, so generated responses can
+    /// be revalidated. Weak because another rendering could differ in insignificant ways.
+    fn with_weak_etag(self) -> Self {
+        match &self.content {
+            Some(Content {
+                content: Body::Bytes(bytes),
+                ..
+            }) => {
+                let etag = format!("W/\"{:x}-{:08x}\"", bytes.len(), crc32_update(0, bytes));
+                self.with_header("ETag", &etag)
+            }
+            _ => self,
+        }
+    }
+
+    /// Evaluates the request's conditional headers against this response's `ETag` and
+    /// `Last-Modified`, in RFC 7232 order: `If-Match`, else `If-Unmodified-Since`, may fail with
+    /// 412; then `If-None-Match`, else `If-Modified-Since`, may turn it into a 304. An entity
+    /// tag condition always takes precedence over the date condition paired with it.
+    fn with_preconditions(self, request: &Request) -> Self {
+        if self.status_code.code != status_codes::OK.code {
+            return self;
         }
+        let header = |name: &str| self.headers.iter().find(|(key, _)| key == name);
+        let etag = header("ETag").map(|(_, value)| value.as_str());
+        let last_modified = header("Last-Modified").and_then(|(_, value)| parse_http_date(value));
+        let condition = |name: &str| request.header(name);
+        let date_condition = |name: &str| condition(name).and_then(parse_http_date);
 
-        if let Some((key, value)) = header_line.split_once(": ") {
-            headers.insert(key.to_owned(), value.to_owned());
+        let precondition_failed = match condition("If-Match") {
+            Some(tags) => !etag_list_matches(tags, etag, true),
+            None => matches!(
+                (date_condition("If-Unmodified-Since"), last_modified),
+                (Some(since), Some(modified)) if modified > since
+            ),
+        };
+        if precondition_failed {
+            return Self::empty_response(&status_codes::PRECONDITION_FAILED);
+        }
+        let not_modified = match condition("If-None-Match") {
+            Some(tags) => etag_list_matches(tags, etag, false),
+            None => matches!(
+                (date_condition("If-Modified-Since"), last_modified),
+                (Some(since), Some(modified)) if modified <= since
+            ),
+        };
+        if not_modified {
+            Self {
+                status_code: &status_codes::NOT_MODIFIED,
+                content: None,
+                ..self
+            }
         } else {
-            return Err(RequestParseError::InvalidHeader);
+            self
         }
     }
-    Ok(headers)
-}
 
-fn parse_request(mut stream: &TcpStream) -> Result<Request, RequestParseError> {
-    let mut reader = BufReader::new(&mut stream);
+    /// Gives a bodiless error response the `--error-page` configured for its status, if any.
+    fn with_error_page(mut self) -> Self {
+        if self.content.is_some() {
+            return self;
+        }
+        if let Some((_, page)) = CONFIGURATION
+            .error_pages
+            .iter()
+            .find(|(code, _)| *code == self.status_code.code)
+        {
+            self.content = Some(Content {
+                mime_type: "text/html".to_owned(),
+                content: Body::Bytes(page.clone()),
+            });
+        }
+        self
+    }
 
-    let mut start_line = String::new();
-    reader
-        .read_line(&mut start_line)
-        .map_err(|_| RequestParseError::CouldNotReadStartLine)?;
-    let mut split_iter = start_line.split(' ');
+    /// Gives bodiless error responses a short plain-text body in the client's preferred language.
+    fn with_localized_error_body(mut self, accept_language: Option<&str>) -> Self {
+        if self.status_code.code < 400 || self.content.is_some() {
+            return self;
+        }
+        let language = accept_language
+            .map(negotiate_language)
+            .unwrap_or(DEFAULT_LANGUAGE);
+        let text = localized_reason(self.status_code, language);
+        self.content = Some(Content {
+            mime_type: "text/plain; charset=utf-8".to_owned(),
+            content: Body::Bytes(text.as_bytes().to_vec()),
+        });
+        // Caches must not hand this body to clients preferring another language.
+        self.with_header("Content-Language", language)
+            .with_header("Vary", "Accept-Language")
+    }
 
-    let verb_str = split_iter
-        .next()
-        .ok_or(RequestParseError::InvalidStructure)?;
-    let path_str = split_iter
-        .next()
-        .ok_or(RequestParseError::InvalidStructure)?
-        .to_owned();
-    let vers_str = split_iter
-        .next()
-        .ok_or(RequestParseError::InvalidStructure)?
-        .to_owned();
+    fn with_connection_header(self, version: &str, keep_alive: bool) -> Self {
+        match (keep_alive, version) {
+            (false, _) => self.with_header("Connection", "close"),
+            (true, "HTTP/1.0") => self.with_header("Connection", "keep-alive"),
+            (true, _) if CONFIGURATION.force_connection.is_some() => {
+                self.with_header("Connection", "keep-alive")
+            }
+            (true, _) => self,
+        }
+    }
 
-    let verb = match verb_str {
-        "GET" => Ok(Verb::Get),
-        "POST" => Ok(Verb::Post),
-        _ => Err(RequestParseError::InvalidVerb),
-    }?;
+    /// Whether the response sets `key` itself, including the content headers it derives.
+    fn has_header(&self, key: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(key))
+            || (self.content.is_some() && key.eq_ignore_ascii_case("Content-Type"))
+            || key.eq_ignore_ascii_case("Content-Length")
+    }
 
-    let headers = read_headers(&mut reader)?;
+    fn body_len(&self) -> usize {
+        self.content
+            .as_ref()
+            .map_or(0, |content| content.content.len())
+    }
 
-    let content = if let Some(length_str) = headers.get("Content-Length") {
-        let content_length = length_str
-            .parse::<usize>()
-            .map_err(|_| RequestParseError::InvalidContentLength)?;
-        let mut buffer: Vec<u8> = vec![0; content_length];
+    fn with_content_type(mut self, mime_type: &str) -> Self {
+        if let Some(content) = self.content.as_mut() {
+            content.mime_type = mime_type.to_owned();
+        }
+        self
+    }
 
-        reader
-            .read_exact(&mut buffer)
-            .map_err(|_| RequestParseError::CouldNotReadBody)?;
+    /// Keeps caches from storing the response; `Pragma` covers HTTP/1.0 caches.
+    fn no_cache(self) -> Self {
+        self.with_header("Cache-Control", "no-store")
+            .with_header("Pragma", "no-cache")
+    }
 
-        Some(buffer)
-    } else {
-        None
+    fn with_close_connection(mut self) -> Self {
+        self.close_connection = true;
+        self
+    }
+
+    /// Appends a header; repeated names (such as `Set-Cookie`) are each written on their own line.
+    fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+const DEFAULT_LANGUAGE: &str = "en";
+const SUPPORTED_LANGUAGES: [&str; 2] = ["en", "fr"];
+
+/// Whether an `Accept` header ranks `application/json` above `alternative`, which wins ties.
+fn prefers_json(accept: &str, alternative: &str) -> bool {
+    let quality_of = |wanted: &str| {
+        accept
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let media = parts.next().unwrap_or_default().trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                let (kind, _) = wanted.split_once('/').unwrap_or((wanted, ""));
+                let matches = media == wanted || media == "*/*" || media == format!("{}/*", kind);
+                matches.then_some(quality)
+            })
+            .fold(0.0, f32::max)
     };
+    quality_of("application/json") > quality_of(alternative)
+}
 
-    Ok(Request {
-        verb,
-        path: path_str,
-        version: vers_str,
-        headers,
-        body: content,
-    })
+/// Formats seconds since the Unix epoch as an ISO 8601 UTC timestamp.
+fn iso8601_utc(unix_secs: u64) -> String {
+    let secs_of_day = unix_secs % 86_400;
+    let (year, month, day) = civil_from_days((unix_secs / 86_400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
 }
 
-fn handle_request(request: &Request) -> Response {
-    dbg!("handling: {:?}", request);
-    if let Some(path) = request.path.strip_prefix('/') {
-        match path {
-            "" => Response::empty_response(&status_codes::OK),
-            "user-agent" => Response::text_reponse(
-                &status_codes::OK,
-                request
-                    .headers
-                    .get("User-Agent")
-                    .expect("must have User-Agent header"),
-            ),
-            _ => match path.split_once('/') {
-                Some(("echo", content)) => Response::text_reponse(&status_codes::OK, content),
-                Some(("files", filename)) => {
-                    let path = [
-                        CONFIGURATION
-                            .files_root
-                            .as_ref()
-                            .expect("files_root should be configured"),
-                        &filename.to_owned(),
-                    ]
-                    .iter()
-                    .collect();
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
 
-                    match request.verb {
-                        Verb::Get => Response::file_response(&path),
-                        Verb::Post => {
-                            let body = request
-                                .body
-                                .as_ref()
-                                .expect("body should be present on request");
+/// Formats seconds since the Unix epoch as an HTTP date (IMF-fixdate), as in `Last-Modified`.
+fn http_date(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs_of_day = unix_secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
 
-                            match std::fs::write(path, body) {
-                                Ok(_) => Response::empty_response(&status_codes::CREATED),
-                                Err(_) => {
-                                    Response::empty_response(&status_codes::INTERNAL_SERVER_ERROR)
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => Response::empty_response(&status_codes::NOT_FOUND),
-            },
-        }
-    } else {
-        Response::empty_response(&status_codes::NOT_FOUND)
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`. The obsolete RFC 850 and
+/// asctime forms are not accepted, so conditions using them are ignored.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let (_, rest) = value.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let time: Vec<u64> = parts
+        .next()?
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    if parts.next() != Some("GMT") || parts.next().is_some() || time.len() != 3 {
+        return None;
     }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
 }
 
-fn handle_connection(stream: &TcpStream) {
-    println!("accepted new connection");
-
-    let request = parse_request(stream).expect("request should be parsable");
-    let response = handle_request(&request);
-    response
-        .write_to_stream(stream)
-        .expect("response can be sent back");
+/// Days since the Unix epoch to a `(year, month, day)` civil date, after Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
 }
 
-#[derive(Clone)]
-struct Configuration {
-    files_root: Option<String>,
+/// The inverse of `civil_from_days`, after Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
 }
 
-impl Configuration {
-    fn from_args(args: &mut std::env::Args) -> Configuration {
-        args.next(); // skip first (program)
-        let directory = if let Some(dir_arg) = args.next() {
-            if dir_arg == "--directory" {
-                args.next()
+/// Whether a comma-separated `If-Match`/`If-None-Match` list names `etag`. Strong comparison
+/// (for `If-Match`) never matches a weak tag; weak comparison ignores the `W/` prefix.
+fn etag_list_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    list.trim() == "*"
+        || list.split(',').map(str::trim).any(|candidate| {
+            if strong {
+                !candidate.starts_with("W/") && !etag.starts_with("W/") && candidate == etag
             } else {
-                None
+                candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
             }
+        })
+}
+
+fn localized_reason(status_code: &StatusCode, language: &str) -> &'static str {
+    match (language, status_code.code) {
+        ("fr", 400) => "Requête invalide",
+        ("fr", 404) => "Introuvable",
+        ("fr", 500) => "Erreur interne du serveur",
+        _ => status_code.status,
+    }
+}
+
+/// Picks the supported language with the highest q-value from an `Accept-Language` header.
+fn negotiate_language(accept_language: &str) -> &'static str {
+    let mut best: Option<(&'static str, f32)> = None;
+    for item in accept_language.split(',') {
+        let mut parts = item.split(';');
+        let tag = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let primary = tag.split('-').next().unwrap_or_default();
+        let language = if primary == "*" {
+            Some(DEFAULT_LANGUAGE)
         } else {
-            None
+            SUPPORTED_LANGUAGES
+                .iter()
+                .copied()
+                .find(|supported| *supported == primary)
         };
+        if let Some(language) = language {
+            if quality > 0.0 && !matches!(best, Some((_, best_quality)) if best_quality >= quality)
+            {
+                best = Some((language, quality));
+            }
+        }
+    }
+    best.map_or(DEFAULT_LANGUAGE, |(language, _)| language)
+}
 
-        Configuration {
-            files_root: directory,
+#[derive(Debug)]
+enum Verb {
+    Get,
+    Post,
+    Head,
+    Options,
+}
+
+impl Verb {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Verb::Get => "GET",
+            Verb::Post => "POST",
+            Verb::Head => "HEAD",
+            Verb::Options => "OPTIONS",
         }
     }
 }
 
-use lazy_static::lazy_static;
+#[derive(Debug)]
+struct Request {
+    verb: Verb,
+    path: String,
+    query: Vec<(String, String)>,
+    /// Matrix parameters (`/name;key=value`) stripped from path segments under `--matrix-params`.
+    path_params: Vec<(String, String)>,
+    version: String,
+    headers: HashMap<String, String>,
+    body: Option<RequestBody>,
+}
 
-lazy_static! {
-    static ref CONFIGURATION: Configuration = Configuration::from_args(&mut std::env::args());
+/// A request body, kept in memory unless it is an upload or larger than
+/// `--spill-threshold-bytes`.
+#[derive(Debug)]
+enum RequestBody {
+    InMemory(BufferedBody),
+    Spilled(SpilledBody),
 }
 
-fn main() {
-    println!("Logs from your program will appear here!");
+/// Body bytes held in memory. They stay reserved against `--max-total-body-bytes` for as long
+/// as this lives, and the buffer goes back to the pool when it drops, unwinding included.
+#[derive(Debug)]
+struct BufferedBody(Vec<u8>);
 
-    let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
+impl BufferedBody {
+    fn empty() -> Self {
+        Self(Vec::new())
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let _ = thread::spawn(move || handle_connection(&stream));
+    /// Reserves `len` bytes of the body budget and a zeroed pooled buffer to read them into.
+    fn reserve(len: usize) -> Result<Self, RequestParseError> {
+        let mut body = Self::empty();
+        body.grow(len)?;
+        Ok(body)
+    }
+
+    /// Extends the body by `len` zeroed bytes, reserved like the rest, and returns them.
+    fn grow(&mut self, len: usize) -> Result<&mut [u8], RequestParseError> {
+        if !BODY_BUDGET.try_reserve(len) {
+            return Err(RequestParseError::BodyBudgetExhausted);
+        }
+        if self.0.capacity() == 0 {
+            self.0 = BODY_BUFFERS.take(0);
+        }
+        let start = self.0.len();
+        self.0.resize(start + len, 0);
+        Ok(&mut self.0[start..])
+    }
+}
+
+impl std::ops::Deref for BufferedBody {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for BufferedBody {
+    fn drop(&mut self) {
+        BODY_BUDGET.release(self.0.len());
+        if self.0.capacity() > 0 {
+            BODY_BUFFERS.give_back(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+/// A body written out to a temporary file, which is removed again when this drops.
+#[derive(Debug)]
+struct SpilledBody {
+    path: PathBuf,
+    len: usize,
+}
+
+impl Drop for SpilledBody {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl RequestBody {
+    fn len(&self) -> usize {
+        match self {
+            RequestBody::InMemory(bytes) => bytes.len(),
+            RequestBody::Spilled(spilled) => spilled.len,
+        }
+    }
+
+    /// Reads the body from the start, wherever it is kept.
+    fn reader(&self) -> std::io::Result<Box<dyn Read + '_>> {
+        match self {
+            RequestBody::InMemory(bytes) => Ok(Box::new(&bytes[..])),
+            RequestBody::Spilled(spilled) => Ok(Box::new(std::fs::File::open(&spilled.path)?)),
+        }
+    }
+
+    /// The body as a response body; a spilled one is streamed back from its file.
+    fn to_response_body(&self) -> std::io::Result<Body> {
+        match self {
+            RequestBody::InMemory(bytes) => Ok(Body::Bytes(bytes.to_vec())),
+            RequestBody::Spilled(spilled) => Ok(Body::File {
+                file: std::fs::File::open(&spilled.path)?,
+                len: spilled.len,
+            }),
+        }
+    }
+}
+
+/// Creates a fresh, still empty temporary file in `--spill-dir` to hold a body.
+fn spill_file() -> Result<(SpilledBody, std::fs::File), RequestParseError> {
+    static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let spilled = SpilledBody {
+        path: CONFIGURATION.spill_dir.join(format!(
+            "http-body.{}.{}.tmp",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )),
+        len: 0,
+    };
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&spilled.path)
+        .map_err(|e| {
+            log!("could not spill request body: {}", e);
+            RequestParseError::CouldNotReadBody
+        })?;
+    Ok((spilled, file))
+}
+
+/// Copies exactly `len` body bytes from the connection into a fresh temporary file.
+fn spill_body(reader: &mut dyn BufRead, len: usize) -> Result<SpilledBody, RequestParseError> {
+    let (mut spilled, mut file) = spill_file()?;
+    match std::io::copy(&mut reader.take(len as u64), &mut file) {
+        Ok(copied) if copied == len as u64 => {
+            spilled.len = len;
+            Ok(spilled)
+        }
+        _ => Err(RequestParseError::CouldNotReadBody),
+    }
+}
+
+/// Decodes a `chunked` body into a fresh temporary file a chunk at a time, so however large
+/// it gets, only the `--max-body-bytes` running total limits it and memory never holds it.
+fn spill_chunked_body(reader: &mut dyn BufRead) -> Result<SpilledBody, RequestParseError> {
+    let (mut spilled, mut file) = spill_file()?;
+    read_chunks(reader, |reader, size| {
+        match std::io::copy(&mut reader.take(size as u64), &mut file) {
+            Ok(copied) if copied == size as u64 => {
+                spilled.len += size;
+                Ok(())
             }
-            Err(e) => {
-                println!("error: {}", e);
+            _ => Err(RequestParseError::CouldNotReadBody),
+        }
+    })?;
+    Ok(spilled)
+}
+
+impl Request {
+    /// The value of header `name`, matched without regard to case like every header name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// HTTP/1.1 connections persist unless closed explicitly; HTTP/1.0 ones only on request.
+    fn wants_keep_alive(&self) -> bool {
+        let connection_has = |token: &str| {
+            self.header("Connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|option| option.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        if self.version == "HTTP/1.0" {
+            connection_has("keep-alive")
+        } else {
+            !connection_has("close")
+        }
+    }
+
+    /// The originating client as reported by a trusted proxy, preferring RFC 7239 `Forwarded`
+    /// over `X-Forwarded-For`. Only meaningful under `--trust-proxy`.
+    fn forwarded_client(&self) -> Option<String> {
+        let forwarded = self.header("Forwarded").and_then(|value| {
+            let first = value.split(',').next()?;
+            first.split(';').find_map(|pair| {
+                let (name, node) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for")
+                    .then(|| forwarded_node_address(node.trim_matches('"')))
+            })
+        });
+        forwarded.or_else(|| {
+            let first = self.header("X-Forwarded-For")?.split(',').next()?;
+            Some(first.trim().to_owned()).filter(|address| !address.is_empty())
+        })
+    }
+
+    /// The first value given for `key`.
+    fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value given for `key`, in order, so `?tag=a&tag=b` yields `["a", "b"]`.
+    fn query_all(&self, key: &str) -> Vec<String> {
+        self.query
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+}
+
+/// Encodes everything but unreserved characters, so `input` can be used as one path segment.
+fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The address part of a `Forwarded` node such as `192.0.2.60:47011` or `[2001:db8::1]:4711`.
+fn forwarded_node_address(node: &str) -> String {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest).to_owned();
+    }
+    match node.split_once(':') {
+        Some((address, port)) if !port.contains(':') => address.to_owned(),
+        _ => node.to_owned(),
+    }
+}
+
+/// Collapses repeated slashes and drops `.` segments, so `/files//sub/./a` routes as
+/// `/files/sub/a`. A `..` segment is refused outright rather than resolved.
+fn normalize_path(path: &str) -> Result<String, RequestParseError> {
+    let Some(rest) = path.strip_prefix('/') else {
+        return Ok(path.to_owned());
+    };
+    let mut segments = Vec::new();
+    for segment in rest.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(RequestParseError::PathTraversal),
+            segment => segments.push(segment),
+        }
+    }
+    let trailing_slash = !segments.is_empty() && (path.ends_with('/') || path.ends_with("/."));
+    Ok(format!(
+        "/{}{}",
+        segments.join("/"),
+        if trailing_slash { "/" } else { "" }
+    ))
+}
+
+/// Removes `;key=value` parameters from every path segment, returning the bare path and them.
+fn split_matrix_params(path: &str) -> (String, Vec<(String, String)>) {
+    let mut params = Vec::new();
+    let segments: Vec<&str> = path
+        .split('/')
+        .map(|segment| {
+            let mut parts = segment.split(';');
+            let name = parts.next().unwrap_or_default();
+            params.extend(parts.filter(|param| !param.is_empty()).map(|param| {
+                match param.split_once('=') {
+                    Some((key, value)) => (percent_decode(key), percent_decode(value)),
+                    None => (percent_decode(param), String::new()),
+                }
+            }));
+            name
+        })
+        .collect();
+    (segments.join("/"), params)
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Matches `name` against a shell-style pattern where `*` is any run and `?` any one byte.
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_matches(&pattern[1..], name)
+                || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &name[1..]),
+        (Some(expected), Some(actual)) if expected == actual => {
+            glob_matches(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+/// Accepts `type/subtype` optionally followed by `; name=value` parameters.
+fn is_valid_media_type(value: &str) -> bool {
+    let mut parts = value.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let valid_essence = match essence.split_once('/') {
+        Some((kind, subtype)) => is_token(kind) && is_token(subtype),
+        None => false,
+    };
+
+    valid_essence
+        && parts.all(|parameter| match parameter.trim().split_once('=') {
+            Some((name, value)) => is_token(name) && is_token(value.trim_matches('"')),
+            None => false,
+        })
+}
+
+#[derive(Debug)]
+enum RequestParseError {
+    InvalidVerb,
+    CouldNotReadStartLine,
+    InvalidStructure,
+    CouldNotReadHeader,
+    InvalidHeader,
+    InvalidContentLength,
+    CouldNotReadBody,
+    ClientDisconnected,
+    BodyTooLarge,
+    UriTooLong,
+    Http2Preface,
+    BodyBudgetExhausted,
+    ConflictingFraming,
+    HeaderValueTooLarge,
+    LengthRequired,
+    DuplicateHost,
+    DuplicateFraming,
+    ConnectNotSupported,
+    AuthorityMismatch,
+    PathTraversal,
+    PathTooDeep,
+    UnsupportedVersion,
+    InvalidUtf8,
+}
+
+impl RequestParseError {
+    fn to_response(&self) -> Response<'static> {
+        let response = match self.status_code().code {
+            503 => Response::server_busy(),
+            405 => Response::empty_response(self.status_code())
+                .with_header("Allow", "GET, HEAD, POST, OPTIONS"),
+            _ => Response::empty_response(self.status_code()),
+        };
+        response.with_error_page()
+    }
+
+    fn status_code(&self) -> &'static StatusCode {
+        match self {
+            RequestParseError::BodyTooLarge => &status_codes::CONTENT_TOO_LARGE,
+            RequestParseError::UriTooLong => &status_codes::URI_TOO_LONG,
+            RequestParseError::LengthRequired => &status_codes::LENGTH_REQUIRED,
+            RequestParseError::ConnectNotSupported => &status_codes::METHOD_NOT_ALLOWED,
+            RequestParseError::HeaderValueTooLarge => {
+                &status_codes::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            RequestParseError::Http2Preface | RequestParseError::UnsupportedVersion => {
+                &status_codes::HTTP_VERSION_NOT_SUPPORTED
+            }
+            RequestParseError::BodyBudgetExhausted => &status_codes::SERVICE_UNAVAILABLE,
+            _ => &status_codes::BAD_REQUEST,
+        }
+    }
+}
+
+fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Reads one line, reporting EOF and connection resets as `ClientDisconnected`. A CR anywhere
+/// but directly before the terminating LF is rejected, as it is a request smuggling vector.
+/// At most `limit` bytes, terminator included, are read; a longer line is `too_long`.
+fn read_request_line(
+    reader: &mut dyn BufRead,
+    line: &mut String,
+    limit: usize,
+    too_long: RequestParseError,
+    error: RequestParseError,
+) -> Result<(), RequestParseError> {
+    let mut bytes = Vec::new();
+    let mut bounded = reader.take(limit.saturating_add(1) as u64);
+    match bounded.read_until(b'\n', &mut bytes) {
+        Ok(0) => Err(RequestParseError::ClientDisconnected),
+        Ok(read) if read > limit => Err(too_long),
+        Ok(_) => {
+            // Malformed bytes are the client's fault, not a failure to read from it.
+            let text = std::str::from_utf8(&bytes).map_err(|_| RequestParseError::InvalidUtf8)?;
+            line.push_str(text);
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            let content = content.strip_suffix('\r').unwrap_or(content);
+            if content.contains('\r') {
+                Err(RequestParseError::InvalidStructure)
+            } else {
+                Ok(())
+            }
+        }
+        Err(e) if is_disconnect(&e) => Err(RequestParseError::ClientDisconnected),
+        Err(_) => Err(error),
+    }
+}
+
+/// Reads header lines up to the blank line ending them. Names are stored lowercased, so every
+/// lookup is case-insensitive and case variants of one header cannot hide behind each other.
+fn read_headers(reader: &mut dyn BufRead) -> Result<HashMap<String, String>, RequestParseError> {
+    // Room for a generous name around the largest allowed value, so a line is cut off before
+    // it is buffered whether or not it turns out to be a well-formed header.
+    const MAX_HEADER_NAME_BYTES: usize = 256;
+    let line_limit = MAX_HEADER_NAME_BYTES + ": \r\n".len() + CONFIGURATION.max_header_value_bytes;
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let mut header_line = String::new();
+        read_request_line(
+            reader,
+            &mut header_line,
+            line_limit,
+            RequestParseError::HeaderValueTooLarge,
+            RequestParseError::CouldNotReadHeader,
+        )?;
+        let header_line = header_line.trim_end();
+        if header_line.is_empty() {
+            break;
+        }
+
+        if let Some((key, value)) = header_line.split_once(": ") {
+            if value.len() > CONFIGURATION.max_header_value_bytes {
+                return Err(RequestParseError::HeaderValueTooLarge);
+            }
+            let key = key.to_ascii_lowercase();
+            // The map keeps only the last copy, so repeats that decide routing or framing have
+            // to be caught here: hops that pick different copies would read the request
+            // differently, which is how requests get smuggled.
+            if headers.contains_key(&key) {
+                match key.as_str() {
+                    "host" => return Err(RequestParseError::DuplicateHost),
+                    "content-length" | "transfer-encoding" => {
+                        return Err(RequestParseError::DuplicateFraming)
+                    }
+                    _ => {}
+                }
             }
+            headers.insert(key, value.to_owned());
+        } else {
+            return Err(RequestParseError::InvalidHeader);
+        }
+    }
+    Ok(headers)
+}
+
+/// Decodes a `chunked` body, calling `chunk` with each chunk's size to consume that many bytes
+/// from `reader`, and returns the decoded length. Chunk extensions (`1a;name=value`) are
+/// skipped and trailer fields are read and discarded.
+fn read_chunks(
+    reader: &mut dyn BufRead,
+    mut chunk: impl FnMut(&mut dyn BufRead, usize) -> Result<(), RequestParseError>,
+) -> Result<usize, RequestParseError> {
+    // A size plus extensions; nothing legitimate comes close.
+    const MAX_SIZE_LINE_BYTES: usize = 1024;
+    let mut total: usize = 0;
+    loop {
+        let mut size_line = String::new();
+        read_request_line(
+            reader,
+            &mut size_line,
+            MAX_SIZE_LINE_BYTES,
+            RequestParseError::InvalidStructure,
+            RequestParseError::CouldNotReadBody,
+        )?;
+        let size_str = size_line.split(';').next().unwrap_or_default().trim();
+        let size = parse_length(size_str, 16).ok_or(RequestParseError::InvalidContentLength)?;
+        if size == 0 {
+            read_headers(reader)?;
+            return Ok(total);
+        }
+        // Checked before reading the chunk, so an oversized one is refused without buffering.
+        total = match total.checked_add(size) {
+            Some(total) if total <= CONFIGURATION.max_body_bytes => total,
+            _ => return Err(RequestParseError::BodyTooLarge),
+        };
+        chunk(&mut *reader, size)?;
+        let mut terminator = [0; 2];
+        reader
+            .read_exact(&mut terminator)
+            .map_err(|_| RequestParseError::CouldNotReadBody)?;
+        if &terminator != b"\r\n" {
+            return Err(RequestParseError::InvalidStructure);
+        }
+    }
+}
+
+/// Decodes a `chunked` body into memory, reserving body budget as each chunk arrives.
+fn read_chunked_body(reader: &mut dyn BufRead) -> Result<BufferedBody, RequestParseError> {
+    let mut body = BufferedBody::empty();
+    read_chunks(reader, |reader, size| {
+        reader
+            .read_exact(body.grow(size)?)
+            .map_err(|_| RequestParseError::CouldNotReadBody)
+    })?;
+    Ok(body)
+}
+
+/// Splits an absolute-form target (`http://host/path`) into its authority and the rest, which
+/// lacks the leading `/` when the path is empty; other targets come back with no authority.
+fn split_absolute_form(target: &str) -> (Option<&str>, &str) {
+    let scheme_end = match target.find("://") {
+        Some(end)
+            if matches!(
+                target[..end].to_ascii_lowercase().as_str(),
+                "http" | "https"
+            ) =>
+        {
+            end
         }
+        _ => return (None, target),
+    };
+    let rest = &target[scheme_end + 3..];
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, remainder) = rest.split_at(authority_end);
+    (Some(authority), remainder)
+}
+
+/// Parses a Content-Length (`radix` 10) or chunk size (16). Only digits are allowed: the
+/// standard parsers also take a leading `+`, which other hops may read differently.
+fn parse_length(text: &str, radix: u32) -> Option<usize> {
+    if text.is_empty() || !text.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    usize::from_str_radix(text, radix).ok()
+}
+
+/// Reads past `len` body bytes without holding on to them, keeping the connection framed.
+fn skip_bytes(reader: &mut dyn BufRead, len: usize) -> Result<(), RequestParseError> {
+    match std::io::copy(&mut reader.take(len as u64), &mut std::io::sink()) {
+        Ok(copied) if copied == len as u64 => Ok(()),
+        _ => Err(RequestParseError::CouldNotReadBody),
+    }
+}
+
+/// Whether the handler for this request looks at its body; all others have it skipped unread.
+fn route_reads_body(verb: &Verb, path: &str) -> bool {
+    matches!(verb, Verb::Post)
+        && (path == "/post-echo" || (path.starts_with("/files/") && !CONFIGURATION.read_only))
+}
+
+/// Reads the next request from a connection; bytes past its end stay buffered in `reader`.
+/// An interim `100 Continue` goes out through `stream`.
+fn parse_request(
+    reader: &mut dyn BufRead,
+    mut stream: impl Write,
+) -> Result<Request, RequestParseError> {
+    let mut start_line = String::new();
+    // The longest method and the version around a target of the largest allowed size; the
+    // line is cut off there, so an endless target is refused without being buffered.
+    let start_line_limit = CONFIGURATION.max_uri_bytes + "OPTIONS  HTTP/1.1\r\n".len();
+    read_request_line(
+        reader,
+        &mut start_line,
+        start_line_limit,
+        RequestParseError::UriTooLong,
+        RequestParseError::CouldNotReadStartLine,
+    )?;
+    // Prior-knowledge HTTP/2 clients open with `PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n`, which
+    // only looks like an HTTP/1 start line.
+    if start_line == "PRI * HTTP/2.0\r\n" {
+        return Err(RequestParseError::Http2Preface);
+    }
+    let mut split_iter = start_line.trim_end().split(' ');
+
+    let verb_str = split_iter
+        .next()
+        .ok_or(RequestParseError::InvalidStructure)?;
+    let target_str = split_iter
+        .next()
+        .ok_or(RequestParseError::InvalidStructure)?;
+    if target_str.len() > CONFIGURATION.max_uri_bytes {
+        return Err(RequestParseError::UriTooLong);
+    }
+    let vers_str = split_iter
+        .next()
+        .ok_or(RequestParseError::InvalidStructure)?
+        .to_owned();
+    if split_iter.next().is_some() {
+        return Err(RequestParseError::InvalidStructure);
+    }
+    // A well-formed version this server does not speak is 505 rather than a malformed line.
+    // Any HTTP/1 minor version is served as HTTP/1.1, the highest one it implements.
+    match vers_str
+        .strip_prefix("HTTP/")
+        .map(|number| number.as_bytes())
+    {
+        Some([b'1', b'.', minor]) if minor.is_ascii_digit() => {}
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            return Err(RequestParseError::UnsupportedVersion)
+        }
+        _ => return Err(RequestParseError::InvalidStructure),
+    }
+    let (authority, target_str) = split_absolute_form(target_str);
+    let (path_str, query) = match target_str.split_once('?') {
+        Some((path, query)) => (path.to_owned(), parse_query(query)),
+        None => (target_str.to_owned(), Vec::new()),
+    };
+    let path_str = if authority.is_some() && path_str.is_empty() {
+        "/".to_owned()
+    } else {
+        path_str
+    };
+    let (path_str, path_params) = if CONFIGURATION.matrix_params {
+        split_matrix_params(&path_str)
+    } else {
+        (path_str, Vec::new())
+    };
+    let path_str = normalize_path(&path_str)?;
+    if path_str
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count()
+        > CONFIGURATION.max_path_depth
+    {
+        return Err(RequestParseError::PathTooDeep);
+    }
+
+    let verb = match verb_str {
+        "GET" => Ok(Verb::Get),
+        "POST" => Ok(Verb::Post),
+        "HEAD" => Ok(Verb::Head),
+        "OPTIONS" => Ok(Verb::Options),
+        // Tunnelling is for proxies; this server is an origin and says so.
+        "CONNECT" => Err(RequestParseError::ConnectNotSupported),
+        _ => Err(RequestParseError::InvalidVerb),
+    }?;
+    // The asterisk form only means "the server as a whole", which only OPTIONS can ask about.
+    if path_str == "*" && !matches!(verb, Verb::Options) {
+        return Err(RequestParseError::InvalidStructure);
+    }
+
+    let mut headers = read_headers(reader)?;
+    // An absolute-form target names the host itself, and it overrides any Host header.
+    if let Some(authority) = authority {
+        if let Some(host) = headers.insert("host".to_owned(), authority.to_owned()) {
+            if CONFIGURATION.strict_authority && !host.eq_ignore_ascii_case(authority) {
+                return Err(RequestParseError::AuthorityMismatch);
+            }
+        }
+    }
+
+    // A message framed by both headers can be read differently by each hop it passes through.
+    let header = |name: &str| headers.get(name).map(String::as_str);
+    // The obsolete `identity` coding means no transformation at all, so the body is framed as
+    // if there were no Transfer-Encoding.
+    let transfer_encoding = header("transfer-encoding")
+        .filter(|codings| !codings.trim().eq_ignore_ascii_case("identity"));
+    let has_length = header("content-length").is_some();
+    if has_length && transfer_encoding.is_some() {
+        return Err(RequestParseError::ConflictingFraming);
+    }
+    if CONFIGURATION.require_length
+        && matches!(verb, Verb::Post)
+        && !has_length
+        && transfer_encoding.is_none()
+    {
+        return Err(RequestParseError::LengthRequired);
+    }
+
+    // A HEAD request never carries a body, even when it declares a length: reading one would
+    // swallow the start of the next pipelined request.
+    let (content_length, chunked) = match verb {
+        Verb::Head => (None, false),
+        _ => match transfer_encoding {
+            // Only a final `chunked` coding says where the body ends.
+            Some(codings) => match codings.rsplit(',').next() {
+                Some(last) if last.trim().eq_ignore_ascii_case("chunked") => (None, true),
+                _ => return Err(RequestParseError::InvalidHeader),
+            },
+            None => (header("content-length"), false),
+        },
+    };
+    let content_length = content_length
+        .map(|length_str| {
+            parse_length(length_str, 10).ok_or(RequestParseError::InvalidContentLength)
+        })
+        .transpose()?;
+    if content_length.is_some_and(|length| length > CONFIGURATION.max_body_bytes) {
+        return Err(RequestParseError::BodyTooLarge);
+    }
+    // HTTP/1.0 has no interim responses, so there the expectation is ignored (RFC 9110 10.1.1).
+    if CONFIGURATION.expect_continue
+        && vers_str != "HTTP/1.0"
+        && (content_length.is_some() || chunked)
+        && header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|_| RequestParseError::ClientDisconnected)?;
+    }
+
+    let reads_body = route_reads_body(&verb, &path_str);
+    // Uploads always go through a spill file, whatever their size, and are copied from there
+    // into place; only buffers of a fixed size ever hold them in memory.
+    let streams_body = reads_body && path_str.starts_with("/files/");
+    // Bodies read past unbuffered still count as received.
+    let mut skipped = 0;
+    let content = if let Some(content_length) = content_length.filter(|_| !reads_body) {
+        skip_bytes(reader, content_length)?;
+        skipped = content_length;
+        None
+    } else if chunked && !reads_body {
+        skipped = read_chunks(reader, skip_bytes)?;
+        None
+    } else if let Some(content_length) = content_length.filter(|length| {
+        streams_body
+            || CONFIGURATION
+                .spill_threshold_bytes
+                .is_some_and(|threshold| *length > threshold)
+    }) {
+        Some(RequestBody::Spilled(spill_body(reader, content_length)?))
+    } else if let Some(content_length) = content_length {
+        let mut buffer = BufferedBody::reserve(content_length)?;
+        if reader.read_exact(&mut buffer.0).is_err() {
+            return Err(RequestParseError::CouldNotReadBody);
+        }
+        Some(RequestBody::InMemory(buffer))
+    } else if chunked && streams_body {
+        Some(RequestBody::Spilled(spill_chunked_body(reader)?))
+    } else if chunked {
+        Some(RequestBody::InMemory(read_chunked_body(reader)?))
+    } else if reads_body {
+        // Without a length indicator the body is taken to be empty.
+        Some(RequestBody::InMemory(BufferedBody::empty()))
+    } else {
+        None
+    };
+    let received = content.as_ref().map_or(skipped, RequestBody::len);
+    if received > 0 {
+        REQUESTS_WITH_BODY.fetch_add(1, Ordering::SeqCst);
+        REQUEST_BODY_BYTES.fetch_add(received, Ordering::SeqCst);
+    }
+
+    Ok(Request {
+        verb,
+        path: path_str,
+        query,
+        path_params,
+        version: vers_str,
+        headers,
+        body: content,
+    })
+}
+
+struct RequestSummary {
+    verb: &'static str,
+    path: String,
+    status: u16,
+    bytes: usize,
+    duration: Duration,
+}
+
+impl RequestSummary {
+    /// Expands a `--log-format` string: `%h` client, `%m` method, `%U` path, `%s` status,
+    /// `%b` bytes sent, `%D` duration in microseconds and `%%` a literal `%`. Anything else is
+    /// copied through unchanged.
+    fn access_line(&self, format: &str, client: &str) -> String {
+        let mut line = String::with_capacity(format.len() + self.path.len());
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                line.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('h') => line.push_str(client),
+                Some('m') => line.push_str(self.verb),
+                Some('U') => line.push_str(&self.path),
+                Some('s') => line.push_str(&self.status.to_string()),
+                Some('b') => line.push_str(&self.bytes.to_string()),
+                Some('D') => line.push_str(&self.duration.as_micros().to_string()),
+                Some('%') => line.push('%'),
+                Some(other) => {
+                    line.push('%');
+                    line.push(other);
+                }
+                None => line.push('%'),
+            }
+        }
+        line
+    }
+}
+
+/// A parsed request and the response about to be written for it, as captured for tests that
+/// assert on what the server handled without parsing the bytes on the wire again.
+#[cfg(test)]
+#[derive(Debug)]
+struct Exchange {
+    verb: &'static str,
+    path: String,
+    headers: HashMap<String, String>,
+    status: u16,
+    response_headers: Vec<(String, String)>,
+    body_len: usize,
+}
+
+#[cfg(test)]
+impl Exchange {
+    fn of(request: &Request, response: &Response) -> Self {
+        Self {
+            verb: request.verb.as_str(),
+            path: request.path.clone(),
+            headers: request.headers.clone(),
+            status: response.status_code.code,
+            response_headers: response.headers.clone(),
+            body_len: response.body_len(),
+        }
+    }
+}
+
+/// Fixed-size ring buffer of the most recently handled requests, newest last.
+struct RequestLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<RequestSummary>>,
+}
+
+impl RequestLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn record(&self, summary: RequestSummary) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("request log lock poisoned");
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(summary);
+    }
+
+    fn to_json(&self) -> String {
+        let entries = self.entries.lock().expect("request log lock poisoned");
+        let items: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"method\":\"{}\",\"path\":\"{}\",\"status\":{},\"bytes\":{},\"duration_us\":{}}}",
+                    entry.verb,
+                    json_escape(&entry.path),
+                    entry.status,
+                    entry.bytes,
+                    entry.duration.as_micros()
+                )
+            })
+            .collect();
+        format!("[{}]", items.join(","))
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes to a temporary sibling first so readers never observe a partially written file.
+fn write_file_atomically(path: &PathBuf, content: &mut dyn Read) -> std::io::Result<()> {
+    static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = write_upload(&temp_path, content).and_then(|_| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Creates `path` with `content`, giving it the `--upload-mode` permissions where supported.
+fn write_upload(path: &PathBuf, content: &mut dyn Read) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = CONFIGURATION.upload_mode {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(mode);
+        let mut file = options.open(path)?;
+        // The mode given to open is filtered through the umask; set it again to get it exactly.
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+        return std::io::copy(content, &mut file).map(|_| ());
+    }
+    std::io::copy(content, &mut options.open(path)?).map(|_| ())
+}
+
+/// The parsed query as a JSON object mapping each key to all of its values.
+fn query_json(request: &Request) -> String {
+    let keys = request.query.iter().map(|(key, _)| key.as_str()).unique();
+    let fields: Vec<String> = keys
+        .map(|key| {
+            let values: Vec<String> = request
+                .query_all(key)
+                .iter()
+                .map(|value| format!("\"{}\"", json_escape(value)))
+                .collect();
+            format!("\"{}\":[{}]", json_escape(key), values.join(","))
+        })
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn path_params_json(request: &Request) -> String {
+    let fields: Vec<String> = request
+        .path_params
+        .iter()
+        .map(|(key, value)| format!("\"{}\":\"{}\"", json_escape(key), json_escape(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Renders `name` from the template root, replacing each `{{key}}` with the first `key` query
+/// value; keys missing from the query render as nothing.
+fn render_template(name: &str, request: &Request) -> Response<'static> {
+    let Some(root) = CONFIGURATION.template_root.as_ref() else {
+        return Response::not_found();
+    };
+    if name.is_empty() || name.split('/').any(|segment| segment == "..") {
+        return Response::not_found();
+    }
+    let Ok(template) = std::fs::read_to_string(PathBuf::from(root).join(name)) else {
+        return Response::not_found();
+    };
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(
+            request
+                .query(rest[start + 2..start + 2 + end].trim())
+                .unwrap_or(""),
+        );
+        rest = &rest[start + 2 + end + 2..];
+    }
+    rendered.push_str(rest);
+    Response::text_reponse(&status_codes::OK, &rendered)
+}
+
+/// Whether `relative` names something beneath a regular file under the files root, such as
+/// `a.txt/b` where `a.txt` is a file; that can never exist, whatever the method.
+fn traverses_file(relative: &str) -> bool {
+    let Some(root) = CONFIGURATION.files_root.as_ref() else {
+        return false;
+    };
+    let mut current = PathBuf::from(root);
+    let mut segments = relative
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .peekable();
+    while let Some(segment) = segments.next() {
+        if segments.peek().is_none() {
+            return false;
+        }
+        current.push(segment);
+        if current.metadata().is_ok_and(|metadata| !metadata.is_dir()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Streams the directory `dir` under the files root as an attachment. `dir` goes through the
+/// same normalization as request paths, so it cannot climb out of the root.
+fn zip_response(dir: &str) -> Response<'static> {
+    let Some(root) = CONFIGURATION.files_root.as_ref() else {
+        return Response::not_found();
+    };
+    let Ok(relative) = normalize_path(&format!("/{}", dir)) else {
+        return Response::empty_response(&status_codes::BAD_REQUEST);
+    };
+    let path = PathBuf::from(root).join(relative.trim_matches('/'));
+    if !path.is_dir() {
+        return Response::not_found();
+    }
+    let archive = match ZipArchive::of_directory(&path) {
+        Ok(archive) => archive,
+        Err(e) => return Response::internal_error(&e),
+    };
+    let name = path
+        .file_name()
+        .map_or("files".to_owned(), |name| {
+            name.to_string_lossy().into_owned()
+        })
+        .replace(['"', '\\'], "_");
+    Response::body_response(&status_codes::OK, "application/zip", Body::Zip(archive)).with_header(
+        "Content-Disposition",
+        &format!("attachment; filename=\"{}.zip\"", name),
+    )
+}
+
+/// Lists a directory under the files root as HTML, or as a JSON array of `name`/`size`/`type`
+/// entries when the `Accept` header prefers it. `?sort=name|size|mtime&order=asc|desc` picks
+/// the order, by default name ascending.
+fn directory_listing(path: &PathBuf, request: &Request) -> Response<'static> {
+    let descending = match request.query("order") {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Response::empty_response(&status_codes::BAD_REQUEST),
+    };
+    let sort = request.query("sort").unwrap_or("name");
+    if !matches!(sort, "name" | "size" | "mtime") {
+        return Response::empty_response(&status_codes::BAD_REQUEST);
+    }
+
+    let mut entries: Vec<(String, u64, &str, std::time::SystemTime)> = match std::fs::read_dir(path)
+    {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok()?;
+                let kind = if metadata.is_dir() { "dir" } else { "file" };
+                Some((
+                    entry.file_name().to_string_lossy().into_owned(),
+                    metadata.len(),
+                    kind,
+                    metadata.modified().unwrap_or(std::time::UNIX_EPOCH),
+                ))
+            })
+            .collect(),
+        Err(e) => {
+            log!("could not list {}: {}", path.display(), e);
+            return Response::not_found();
+        }
+    };
+    entries.sort_by(|a, b| {
+        let by_key = match sort {
+            "size" => a.1.cmp(&b.1),
+            "mtime" => a.3.cmp(&b.3),
+            _ => std::cmp::Ordering::Equal,
+        };
+        by_key.then_with(|| a.0.cmp(&b.0))
+    });
+    if descending {
+        entries.reverse();
+    }
+
+    let accept = request.header("Accept");
+    if accept.is_some_and(|accept| prefers_json(accept, "text/html")) {
+        let items: Vec<String> = entries
+            .iter()
+            .map(|(name, size, kind, _)| {
+                format!(
+                    "{{\"name\":\"{}\",\"size\":{},\"type\":\"{}\"}}",
+                    json_escape(name),
+                    size,
+                    kind
+                )
+            })
+            .collect();
+        return Response::json_response(&status_codes::OK, format!("[{}]", items.join(",")));
+    }
+
+    // Links are absolute, so they resolve the same whether or not the directory was requested
+    // with a trailing slash.
+    let base = request.path.trim_end_matches('/');
+    let items: String = entries
+        .iter()
+        .map(|(name, _, kind, _)| {
+            let slash = if *kind == "dir" { "/" } else { "" };
+            let href = format!("{}/{}{}", base, percent_encode(name), slash);
+            format!(
+                "<li><a href=\"{}\">{}{}</a></li>\n",
+                html_escape(&href),
+                html_escape(name),
+                slash
+            )
+        })
+        .collect();
+    Response::generated_response(
+        &status_codes::OK,
+        "text/html; charset=utf-8",
+        format!("<!DOCTYPE html>\n<ul>\n{}</ul>\n", items).into_bytes(),
+    )
+}
+
+/// Compares secrets in time that depends only on their lengths, not on where they first
+/// differ, so a token cannot be guessed a byte at a time from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// `GET /admin/dashboard`: live counters as a small self-contained HTML page, for requests
+/// bearing `--admin-token`.
+fn admin_dashboard(request: &Request, token: &str) -> Response<'static> {
+    let authorized = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Response::empty_response(&status_codes::UNAUTHORIZED)
+            .with_header("WWW-Authenticate", "Bearer");
+    }
+    let row = |label: &str, value: &str| {
+        format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            html_escape(label),
+            html_escape(value)
+        )
+    };
+    let counters = [
+        row(
+            "Active connections",
+            &ACTIVE_CONNECTIONS.load(Ordering::SeqCst).to_string(),
+        ),
+        row(
+            "Requests handled",
+            &REQUESTS_HANDLED.load(Ordering::SeqCst).to_string(),
+        ),
+        row("Uptime", &format!("{}s", STARTED.elapsed().as_secs())),
+        row(
+            "Files root",
+            CONFIGURATION.files_root.as_deref().unwrap_or("-"),
+        ),
+        row(
+            "Template root",
+            CONFIGURATION.template_root.as_deref().unwrap_or("-"),
+        ),
+        row(
+            "Spill directory",
+            &CONFIGURATION.spill_dir.to_string_lossy(),
+        ),
+    ]
+    .concat();
+    let statuses: String = RESPONSES_BY_STATUS
+        .lock()
+        .expect("status counts lock poisoned")
+        .iter()
+        .map(|(status, count)| row(&format!("Status {}", status), &count.to_string()))
+        .collect();
+    Response::generated_response(
+        &status_codes::OK,
+        "text/html; charset=utf-8",
+        format!(
+            "<!DOCTYPE html>\n<title>Dashboard</title>\n<table>\n{}{}</table>\n",
+            counters, statuses
+        )
+        .into_bytes(),
+    )
+    .no_cache()
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Verifies the configured files root can be listed and, unless `--read-only` means nothing
+/// is ever written there, written to.
+fn check_files_root() -> std::io::Result<()> {
+    let Some(root) = CONFIGURATION.files_root.as_ref() else {
+        return Ok(());
+    };
+    std::fs::read_dir(root)?;
+    if CONFIGURATION.read_only {
+        return Ok(());
+    }
+    let probe = PathBuf::from(root).join(format!(".health-{}", std::process::id()));
+    std::fs::write(&probe, b"ok")?;
+    std::fs::remove_file(&probe)
+}
+
+/// Timestamps marking a request's progress through the connection pipeline.
+struct RequestContext {
+    started: Instant,
+    parsed: Instant,
+    /// When handling should give up, from `--max-request-duration`.
+    deadline: Option<Instant>,
+    /// How long writing the previous response on this connection took; zero for the first.
+    previous_write: Duration,
+}
+
+impl RequestContext {
+    fn deadline_passed(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// A `Server-Timing` value for `--server-timing`. It goes out in the headers, before this
+    /// response is written, so `write` is that of the previous response on the connection.
+    fn server_timing(&self) -> String {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        format!(
+            "parse;dur={:.3}, handle;dur={:.3}, write;dur={:.3};desc=\"previous response\"",
+            millis(self.parsed - self.started),
+            millis(self.parsed.elapsed()),
+            millis(self.previous_write)
+        )
+    }
+
+    /// Phase durations so far. This body is built before it is written, so `write_us` is
+    /// that of the previous response on the connection.
+    fn timing_json(&self) -> String {
+        format!(
+            "{{\"parse_us\":{},\"handle_us\":{},\"write_us\":{}}}",
+            (self.parsed - self.started).as_micros(),
+            self.parsed.elapsed().as_micros(),
+            self.previous_write.as_micros()
+        )
+    }
+}
+
+const ROOT_METHODS: &str = "GET, HEAD, OPTIONS";
+const FILES_READ_ONLY_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// The demonstration routes that `--disable-demo-routes` takes out of routing.
+fn is_demo_route(path: &str) -> bool {
+    path == "user-agent" || path == "events" || path.starts_with("echo/")
+}
+
+/// Methods served at a path (without its leading `/`), or `None` when no route matches.
+fn allowed_methods(path: &str) -> Option<&'static str> {
+    if CONFIGURATION.disable_demo_routes && is_demo_route(path) {
+        return None;
+    }
+    match path {
+        "" => Some(ROOT_METHODS),
+        "user-agent" | "cookies/set" | "health" | "metrics" | "time" | "events"
+        | "debug/requests" | "debug/query" | "debug/timing" | "debug/body-pool"
+        | "debug/path-params" => Some("GET, HEAD, OPTIONS"),
+        "robots.txt" if CONFIGURATION.robots.is_some() => Some("GET, HEAD, OPTIONS"),
+        "admin/dashboard" if CONFIGURATION.admin_token.is_some() => Some("GET, HEAD, OPTIONS"),
+        "files" => Some("GET, HEAD, OPTIONS"),
+        "post-echo" => Some("POST, OPTIONS"),
+        _ => match path.split_once('/') {
+            Some(("echo", _)) | Some(("template", _)) => Some("GET, HEAD, OPTIONS"),
+            Some(("files", _)) if CONFIGURATION.read_only => Some(FILES_READ_ONLY_METHODS),
+            Some(("files", _)) => Some("GET, HEAD, POST, OPTIONS"),
+            _ => None,
+        },
+    }
+}
+
+fn options_response(path: &str) -> Response<'static> {
+    match (allowed_methods(path), CONFIGURATION.options_unknown) {
+        (Some(methods), _) => {
+            Response::empty_response(&status_codes::OK).with_header("Allow", methods)
+        }
+        (None, OptionsUnknownPolicy::NoContent) => {
+            Response::empty_response(&status_codes::NO_CONTENT).with_header("Allow", "")
+        }
+        (None, OptionsUnknownPolicy::NotFound) => Response::not_found(),
+    }
+}
+
+/// Whether `path` is `prefix` or lies below it: `/static` covers `/static/app.js` but not
+/// `/static-secrets`.
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+fn handle_request<'a>(request: &'a Request, context: &RequestContext) -> Response<'a> {
+    if !CONFIGURATION.allow.is_empty()
+        && !CONFIGURATION.allow.iter().any(|(method, prefix)| {
+            (method == "*" || method == request.verb.as_str()) && is_under(&request.path, prefix)
+        })
+    {
+        return Response::empty_response(&status_codes::FORBIDDEN);
+    }
+    if request.path == "*" {
+        return Response::empty_response(&status_codes::OK)
+            .with_header("Allow", "GET, HEAD, POST, OPTIONS");
+    }
+    if let Some(path) = request.path.strip_prefix('/') {
+        if let Verb::Options = request.verb {
+            return options_response(path);
+        }
+        if CONFIGURATION.disable_demo_routes && is_demo_route(path) {
+            return Response::not_found();
+        }
+        if let Some((_, protocol)) = CONFIGURATION
+            .require_upgrade
+            .iter()
+            .find(|(pattern, _)| glob_matches(pattern.as_bytes(), request.path.as_bytes()))
+        {
+            return Response::empty_response(&status_codes::UPGRADE_REQUIRED)
+                .with_header("Upgrade", protocol)
+                .with_header("Connection", "Upgrade");
+        }
+        match path {
+            "" => match request.verb {
+                Verb::Get | Verb::Head => Response::empty_response(&status_codes::OK),
+                _ => Response::empty_response(&status_codes::METHOD_NOT_ALLOWED)
+                    .with_header("Allow", ROOT_METHODS),
+            },
+            "user-agent" => match request.header("User-Agent") {
+                Some(user_agent) => Response::text_reponse(&status_codes::OK, user_agent),
+                None => Response::empty_response(&status_codes::BAD_REQUEST),
+            },
+            "admin/dashboard" if CONFIGURATION.admin_token.is_some() => admin_dashboard(
+                request,
+                CONFIGURATION.admin_token.as_deref().unwrap_or_default(),
+            ),
+            "debug/requests" => {
+                Response::json_response(&status_codes::OK, REQUEST_LOG.to_json()).no_cache()
+            }
+            "cookies/set" => request.query.iter().fold(
+                Response::empty_response(&status_codes::OK).no_cache(),
+                |response, (name, value)| {
+                    response.with_header("Set-Cookie", &format!("{}={}", name, value))
+                },
+            ),
+            "health" => match request.query("deep") {
+                Some("1") | Some("true") => match check_files_root() {
+                    Ok(()) => Response::text_reponse(&status_codes::OK, "OK"),
+                    Err(e) => {
+                        log!("deep health check failed: {}", e);
+                        Response::service_unavailable()
+                    }
+                },
+                _ => Response::text_reponse(&status_codes::OK, "OK"),
+            },
+            "files" => match (&request.verb, request.query("zip")) {
+                (Verb::Get | Verb::Head, Some(dir)) => zip_response(dir),
+                _ => Response::not_found(),
+            },
+            "robots.txt" if CONFIGURATION.robots.is_some() => Response::content_response(
+                &status_codes::OK,
+                "text/plain",
+                CONFIGURATION.robots.clone().unwrap_or_default(),
+            ),
+            "events" => {
+                // HTTP/1.0 has no chunked coding, so there the end of the stream is the close.
+                let chunked = request.version != "HTTP/1.0";
+                let response = Response::body_response(
+                    &status_codes::OK,
+                    "text/event-stream",
+                    Body::Events {
+                        count: CONFIGURATION.events_count,
+                        chunked,
+                    },
+                )
+                .no_cache();
+                if chunked {
+                    response
+                } else {
+                    response.with_close_connection()
+                }
+            }
+            "time" => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                let timestamp = iso8601_utc(now);
+                let response = match request.header("Accept") {
+                    Some(accept) if prefers_json(accept, "text/plain") => Response::json_response(
+                        &status_codes::OK,
+                        format!("{{\"utc\":\"{}\"}}", timestamp),
+                    ),
+                    _ => Response::text_reponse(&status_codes::OK, &timestamp),
+                };
+                response.with_weak_etag().with_preconditions(request)
+            }
+            "metrics" => Response::generated_response(
+                &status_codes::OK,
+                "text/plain; version=0.0.4",
+                metrics_text().into_bytes(),
+            ),
+            "post-echo" => match request.verb {
+                Verb::Post => {
+                    let body = match request.body.as_ref().map(RequestBody::to_response_body) {
+                        Some(Ok(body)) => body,
+                        Some(Err(e)) => return Response::internal_error(&e),
+                        None => Body::Bytes(Vec::new()),
+                    };
+                    Response::body_response(
+                        &status_codes::OK,
+                        request
+                            .header("Content-Type")
+                            .unwrap_or("application/octet-stream"),
+                        body,
+                    )
+                }
+                _ => Response::empty_response(&status_codes::METHOD_NOT_ALLOWED)
+                    .with_header("Allow", "POST, OPTIONS"),
+            },
+            "debug/query" => Response::json_response(&status_codes::OK, query_json(request)),
+            "debug/path-params" => {
+                Response::json_response(&status_codes::OK, path_params_json(request))
+            }
+            "debug/timing" => Response::json_response(&status_codes::OK, context.timing_json()),
+            "debug/body-pool" => Response::json_response(&status_codes::OK, BODY_BUFFERS.to_json()),
+            _ => match path.split_once('/') {
+                Some(("echo", content)) => {
+                    let echoed = match request.query("transform") {
+                        None => Some(content.to_owned()),
+                        Some("upper") => Some(content.to_uppercase()),
+                        Some("lower") => Some(content.to_lowercase()),
+                        Some("reverse") => Some(content.chars().rev().collect()),
+                        Some(_) => None,
+                    };
+                    match echoed {
+                        Some(mut text) => {
+                            if CONFIGURATION.echo_trailing_newline {
+                                text.push('\n');
+                            }
+                            Response::text_reponse(&status_codes::OK, &text)
+                                .with_weak_etag()
+                                .with_preconditions(request)
+                        }
+                        None => Response::empty_response(&status_codes::BAD_REQUEST),
+                    }
+                }
+                Some(("template", name)) => match request.verb {
+                    Verb::Get | Verb::Head => render_template(name, request),
+                    _ => Response::empty_response(&status_codes::METHOD_NOT_ALLOWED)
+                        .with_header("Allow", "GET, HEAD, OPTIONS"),
+                },
+                Some(("files", filename)) => {
+                    let path: PathBuf = [
+                        CONFIGURATION
+                            .files_root
+                            .as_ref()
+                            .expect("files_root should be configured"),
+                        &filename.to_owned(),
+                    ]
+                    .iter()
+                    .collect();
+                    if traverses_file(filename) {
+                        return Response::not_found();
+                    }
+
+                    // A request that already ran out of time is a sign of a stalled client.
+                    if context.deadline_passed() {
+                        return Response::service_unavailable().with_close_connection();
+                    }
+
+                    match request.verb {
+                        Verb::Get | Verb::Head => {
+                            if CONFIGURATION.directory_listing && path.is_dir() {
+                                return directory_listing(&path, request)
+                                    .with_weak_etag()
+                                    .with_preconditions(request);
+                            }
+                            let response = match request.query("ct") {
+                                Some(ct) if !is_valid_media_type(ct) => {
+                                    return Response::empty_response(&status_codes::BAD_REQUEST)
+                                }
+                                // Only the file itself is retyped, never a 404 in its place.
+                                Some(ct) => {
+                                    let response = Response::file_response(&path);
+                                    if response.status_code.code == status_codes::OK.code {
+                                        response.with_content_type(ct)
+                                    } else {
+                                        response
+                                    }
+                                }
+                                None => Response::file_response(&path),
+                            };
+                            let immutable =
+                                CONFIGURATION
+                                    .immutable_pattern
+                                    .as_ref()
+                                    .is_some_and(|pattern| {
+                                        let name = filename.rsplit('/').next().unwrap_or(filename);
+                                        glob_matches(pattern.as_bytes(), name.as_bytes())
+                                    });
+                            let response = if immutable
+                                && response.status_code.code == status_codes::OK.code
+                            {
+                                response.with_header(
+                                    "Cache-Control",
+                                    "public, max-age=31536000, immutable",
+                                )
+                            } else {
+                                response
+                            };
+                            response.with_preconditions(request)
+                        }
+                        Verb::Post if CONFIGURATION.read_only => {
+                            Response::empty_response(&status_codes::METHOD_NOT_ALLOWED)
+                                .with_header("Allow", FILES_READ_ONLY_METHODS)
+                        }
+                        Verb::Options => unreachable!("OPTIONS is answered before routing"),
+                        // Without a file name the path is the directory itself.
+                        Verb::Post if filename.is_empty() || filename.ends_with('/') => {
+                            Response::empty_response(&status_codes::BAD_REQUEST)
+                        }
+                        Verb::Post => {
+                            let body = request
+                                .body
+                                .as_ref()
+                                .expect("body should be present on request");
+
+                            let Some(_upload) = UPLOAD_SLOTS.try_acquire() else {
+                                return Response::server_busy();
+                            };
+                            let written = body
+                                .reader()
+                                .and_then(|mut content| write_file_atomically(&path, &mut content));
+                            match written {
+                                Ok(_) => Response::empty_response(&status_codes::CREATED),
+                                Err(e) => Response::internal_error(&e),
+                            }
+                        }
+                    }
+                }
+                _ => Response::not_found(),
+            },
+        }
+    } else {
+        Response::not_found()
+    }
+}
+
+fn handle_connection(stream: &Connection, accepted: Instant) {
+    struct Active;
+    impl Drop for Active {
+        fn drop(&mut self) {
+            ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+    let _active = Active;
+
+    if let Err(e) = stream.set_write_timeout(CONFIGURATION.write_timeout) {
+        log!("could not set write timeout: {}", e);
+    }
+    let peer = stream.peer_ip();
+    let Some(_slot) = CONNECTIONS_PER_IP.try_acquire(peer) else {
+        log!("too many connections from {:?}, refusing", peer);
+        let _ = Response::server_busy()
+            .with_header("Connection", "close")
+            .write_to_stream(stream, true);
+        return;
+    };
+
+    let connected = Instant::now();
+    let mut reader = BufReader::new(RateCheckedReader::new(stream));
+    let mut bytes_served = 0;
+    let mut requests_served = 0;
+    let mut previous_write = Duration::ZERO;
+    loop {
+        // Start the clock only once the next request begins arriving, so time spent idle on a
+        // kept-alive connection does not count against it.
+        let Some(idle) = SHUTDOWN.track_idle(stream) else {
+            log!("closing idle connection for shutdown");
+            return;
+        };
+        let filled = reader.fill_buf();
+        drop(idle);
+        match filled {
+            Ok([]) if SHUTDOWN.is_requested() => {
+                log!("closing idle connection for shutdown");
+                return;
+            }
+            // A client hanging up is routine; it is not worth a line per connection.
+            Ok([]) => return,
+            Ok(_) => {}
+            Err(e) => {
+                log!("could not read from connection: {}", e);
+                return;
+            }
+        }
+        let started = Instant::now();
+        if requests_served == 0 {
+            FIRST_BYTE_LATENCY.observe(started - accepted);
+        }
+        reader.get_mut().start_measuring();
+        let parsed = parse_request(&mut reader, stream);
+        reader.get_mut().stop_measuring();
+        let mut request = match parsed {
+            Ok(request) => request,
+            Err(_) if reader.get_ref().too_slow => {
+                log!("client sent below --min-read-rate, closing connection");
+                let _ = Response::empty_response(&status_codes::REQUEST_TIMEOUT)
+                    .with_header("Connection", "close")
+                    .write_to_stream(stream, true);
+                return;
+            }
+            Err(RequestParseError::ClientDisconnected) => return,
+            Err(e) => {
+                log!("could not parse request: {:?}", e);
+                let _ = e
+                    .to_response()
+                    .with_header("Connection", "close")
+                    .write_to_stream(stream, true);
+                return;
+            }
+        };
+        let context = RequestContext {
+            started,
+            parsed: Instant::now(),
+            deadline: CONFIGURATION
+                .max_request_duration
+                .map(|duration| started + duration),
+            previous_write,
+        };
+        let in_flight = SHUTDOWN.track_request();
+        if count_request(&REQUESTS_HANDLED, CONFIGURATION.max_requests) {
+            SHUTDOWN.initiate();
+        }
+        // The slot is held until the response is written, as streaming a file is most of the work.
+        let slot = REQUEST_SLOTS.try_acquire();
+        let response = if slot.is_some() {
+            handle_request(&request, &context)
+        } else {
+            Response::server_busy()
+        };
+        let response = response
+            .with_error_page()
+            .with_localized_error_body(request.header("Accept-Language"));
+        let response = if CONFIGURATION.server_timing {
+            response.with_header("Server-Timing", &context.server_timing())
+        } else {
+            response
+        };
+        // The response that takes the connection past its byte budget is still sent in full,
+        // but it is the last one.
+        let over_byte_cap = CONFIGURATION
+            .max_conn_bytes
+            .is_some_and(|cap| bytes_served + response.body_len() > cap);
+        requests_served += 1;
+        let connection_spent = CONFIGURATION
+            .max_conn_requests
+            .is_some_and(|max| requests_served >= max)
+            || CONFIGURATION
+                .max_conn_lifetime
+                .is_some_and(|lifetime| connected.elapsed() >= lifetime);
+        let server_allows_keep_alive = !response.close_connection
+            && !over_byte_cap
+            && !connection_spent
+            && !SHUTDOWN.is_requested();
+        // A forced keep-alive overrides only what the client asked for: a response that must
+        // end the connection (such as a body left unread) still closes it.
+        let keep_alive = match CONFIGURATION.force_connection {
+            Some(ForceConnection::Close) => false,
+            Some(ForceConnection::KeepAlive) => server_allows_keep_alive,
+            None => request.wants_keep_alive() && server_allows_keep_alive,
+        };
+        let response = response.with_connection_header(&request.version, keep_alive);
+        #[cfg(test)]
+        CAPTURED
+            .lock()
+            .expect("capture lock poisoned")
+            .push(Exchange::of(&request, &response));
+        let writing = Instant::now();
+        let written = response.write_to_stream(stream, !matches!(request.verb, Verb::Head));
+        previous_write = writing.elapsed();
+        drop(in_flight);
+        drop(slot);
+        let status = response.status_code.code;
+        drop(response);
+        // The body is done with once the response is out; give its memory back straight away.
+        drop(request.body.take());
+        let bytes = match written {
+            Ok(bytes) => bytes,
+            Err(e) if is_disconnect(&e) => {
+                log!("client went away mid-response, closing connection: {}", e);
+                return;
+            }
+            Err(e) => {
+                log!("could not send response, closing connection: {}", e);
+                return;
+            }
+        };
+        bytes_served += bytes;
+        let client = CONFIGURATION
+            .trust_proxy
+            .then(|| request.forwarded_client())
+            .flatten()
+            .or_else(|| stream.peer_ip().map(|ip| ip.to_string()))
+            .unwrap_or_else(|| "-".to_owned());
+        let summary = RequestSummary {
+            verb: request.verb.as_str(),
+            path: request.path.clone(),
+            status,
+            bytes,
+            duration: context.started.elapsed(),
+        };
+        if logs_access(status, CONFIGURATION.log_sample) {
+            log!(
+                "{}",
+                summary.access_line(&CONFIGURATION.log_format, &client)
+            );
+        }
+        *RESPONSES_BY_STATUS
+            .lock()
+            .expect("status counts lock poisoned")
+            .entry(status)
+            .or_insert(0) += 1;
+        REQUEST_LOG.record(summary);
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Open connections per client address, for `--max-conn-per-ip`.
+struct ConnectionCounts {
+    limit: Option<usize>,
+    counts: Mutex<HashMap<std::net::IpAddr, usize>>,
+}
+
+impl ConnectionCounts {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a connection from `ip` until the returned slot drops, unless that would exceed
+    /// the limit. Connections whose address is unknown are never limited.
+    fn try_acquire(&self, ip: Option<std::net::IpAddr>) -> Option<ConnectionSlot<'_>> {
+        let (Some(limit), Some(ip)) = (self.limit, ip) else {
+            return Some(ConnectionSlot {
+                counts: self,
+                ip: None,
+            });
+        };
+        let mut counts = self.counts.lock().expect("connection counts lock poisoned");
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            counts: self,
+            ip: Some(ip),
+        })
+    }
+}
+
+struct ConnectionSlot<'a> {
+    counts: &'a ConnectionCounts,
+    ip: Option<std::net::IpAddr>,
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        let mut counts = self
+            .counts
+            .counts
+            .lock()
+            .expect("connection counts lock poisoned");
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Caps the bytes of request bodies buffered at once across all connections.
+struct BodyBudget {
+    limit: Option<usize>,
+    in_use: AtomicUsize,
+}
+
+impl BodyBudget {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        self.in_use
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_use| {
+                in_use.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        if self.limit.is_some() {
+            self.in_use.fetch_sub(bytes, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A counting semaphore that never blocks: `try_acquire` either hands out a permit or refuses.
+/// Permits go back when they drop, so a panicking holder cannot leak one.
+struct Semaphore {
+    limit: Option<usize>,
+    in_use: AtomicUsize,
+}
+
+impl Semaphore {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Permit<'_>> {
+        if let Some(limit) = self.limit {
+            self.in_use
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_use| {
+                    (in_use < limit).then_some(in_use + 1)
+                })
+                .ok()?;
+        }
+        Some(Permit(self))
+    }
+}
+
+struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.0.limit.is_some() {
+            self.0.in_use.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Reusable request body buffers, so steady traffic does not allocate a fresh `Vec` per request.
+struct BufferPool {
+    max_buffers: usize,
+    max_buffer_bytes: usize,
+    buffers: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+}
+
+impl BufferPool {
+    fn new(max_buffers: usize, max_buffer_bytes: usize) -> Self {
+        Self {
+            max_buffers,
+            max_buffer_bytes,
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            allocated: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hands out a zeroed buffer of exactly `len` bytes.
+    fn take(&self, len: usize) -> Vec<u8> {
+        let pooled = self
+            .buffers
+            .lock()
+            .expect("buffer pool lock poisoned")
+            .pop();
+        match pooled {
+            Some(mut buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer.clear();
+                buffer.resize(len, 0);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0; len]
+            }
+        }
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        if buffer.capacity() > self.max_buffer_bytes {
+            return;
+        }
+        let mut buffers = self.buffers.lock().expect("buffer pool lock poisoned");
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let pooled = self
+            .buffers
+            .lock()
+            .expect("buffer pool lock poisoned")
+            .len();
+        format!(
+            "{{\"pooled\":{},\"allocated\":{},\"reused\":{}}}",
+            pooled,
+            self.allocated.load(Ordering::Relaxed),
+            self.reused.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// A number of events allowed per period, written as e.g. `1000/s` or `60/m`.
+#[derive(Clone, Copy)]
+struct Rate {
+    count: u32,
+    per: Duration,
+}
+
+impl std::str::FromStr for Rate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s.split_once('/').unwrap_or((s, "s"));
+        let per = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            _ => return Err(()),
+        };
+        match count.parse() {
+            Ok(count) if count > 0 => Ok(Rate { count, per }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Spaces out accepted connections so that no more than `rate` are handed off per period.
+struct AcceptRateLimiter {
+    interval: Duration,
+    next_slot: Instant,
+}
+
+impl AcceptRateLimiter {
+    fn new(rate: Rate) -> Self {
+        Self {
+            interval: rate.per / rate.count,
+            next_slot: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next_slot > now {
+            thread::sleep(self.next_slot - now);
+        }
+        self.next_slot = self.next_slot.max(now) + self.interval;
+    }
+}
+
+/// How `OPTIONS` answers for a path no route matches.
+#[derive(Clone, Copy)]
+enum OptionsUnknownPolicy {
+    NoContent,
+    NotFound,
+}
+
+impl std::str::FromStr for OptionsUnknownPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "204" => Ok(OptionsUnknownPolicy::NoContent),
+            "404" => Ok(OptionsUnknownPolicy::NotFound),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The `--force-connection` override of what the client asked for.
+#[derive(Clone, Copy)]
+enum ForceConnection {
+    Close,
+    KeepAlive,
+}
+
+impl std::str::FromStr for ForceConnection {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(ForceConnection::Close),
+            "keep-alive" => Ok(ForceConnection::KeepAlive),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Configuration {
+    host: String,
+    port: u16,
+    files_root: Option<String>,
+    request_log_size: usize,
+    accept_rate: Option<Rate>,
+    body_pool_size: usize,
+    body_pool_max_buffer_bytes: usize,
+    not_found_page: Option<Vec<u8>>,
+    /// Bodies for error statuses, from `--error-page CODE=PATH`, loaded at startup.
+    error_pages: Vec<(u16, Vec<u8>)>,
+    template_root: Option<String>,
+    /// Listen on this Unix domain socket path instead of TCP.
+    unix_socket: Option<String>,
+    /// Served at `/robots.txt`, independent of `files_root`.
+    robots: Option<Vec<u8>>,
+    write_timeout: Option<Duration>,
+    max_body_bytes: usize,
+    spill_threshold_bytes: Option<usize>,
+    spill_dir: PathBuf,
+    max_total_body_bytes: Option<usize>,
+    max_concurrent_uploads: Option<usize>,
+    max_concurrent_requests: Option<usize>,
+    /// Unix permission bits for uploaded files, given in octal on the command line.
+    upload_mode: Option<u32>,
+    immutable_pattern: Option<String>,
+    stream_chunk_bytes: usize,
+    max_generated_bytes: Option<usize>,
+    read_only: bool,
+    matrix_params: bool,
+    trust_proxy: bool,
+    require_length: bool,
+    disable_demo_routes: bool,
+    strict_authority: bool,
+    directory_listing: bool,
+    /// Puts the underlying error in 500 bodies; off by default so details do not leak.
+    debug_errors: bool,
+    /// Reports parse, handle and write durations in a `Server-Timing` header.
+    server_timing: bool,
+    /// How many events `/events` sends, one a second, before ending the stream.
+    events_count: usize,
+    echo_trailing_newline: bool,
+    /// Answers `Expect: 100-continue` with an interim response before reading the body.
+    expect_continue: bool,
+    nosniff: bool,
+    options_unknown: OptionsUnknownPolicy,
+    force_connection: Option<ForceConnection>,
+    max_request_duration: Option<Duration>,
+    /// Slowest acceptable request upload in bytes a second, once the window has passed.
+    min_read_rate: Option<usize>,
+    min_read_rate_window: Duration,
+    retry_after: Duration,
+    shutdown_timeout: Duration,
+    accept_backoff_max: Duration,
+    /// Shut down gracefully after this many requests, expecting a supervisor to restart us.
+    max_requests: Option<usize>,
+    max_conn_bytes: Option<usize>,
+    max_conn_requests: Option<usize>,
+    max_conn_lifetime: Option<Duration>,
+    max_conn_per_ip: Option<usize>,
+    max_uri_bytes: usize,
+    max_path_depth: usize,
+    max_header_value_bytes: usize,
+    /// Response headers past either limit are dropped, leaving the content headers intact.
+    max_response_headers: usize,
+    max_response_header_bytes: usize,
+    log_file: Option<String>,
+    log_format: String,
+    /// Fraction of requests written to the access log; server errors are always written.
+    log_sample: f64,
+    log_max_bytes: u64,
+    log_keep: usize,
+    /// Sent on every response that does not set the same header itself.
+    default_headers: Vec<(String, String)>,
+    /// Paths matching a pattern get 426 naming the protocol to upgrade to.
+    require_upgrade: Vec<(String, String)>,
+    /// Enables `/admin/dashboard` for requests with `Authorization: Bearer <token>`.
+    admin_token: Option<String>,
+    /// `(method, path prefix)` pairs from `--allow`; when any are given, all else gets 403.
+    allow: Vec<(String, String)>,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            host: "127.0.0.1".to_owned(),
+            port: 4221,
+            files_root: None,
+            request_log_size: 100,
+            accept_rate: None,
+            body_pool_size: 16,
+            body_pool_max_buffer_bytes: 1024 * 1024,
+            not_found_page: None,
+            error_pages: Vec::new(),
+            robots: None,
+            template_root: None,
+            unix_socket: None,
+            write_timeout: None,
+            max_body_bytes: 64 * 1024 * 1024,
+            spill_threshold_bytes: None,
+            spill_dir: std::env::temp_dir(),
+            max_total_body_bytes: None,
+            max_concurrent_uploads: None,
+            max_concurrent_requests: None,
+            upload_mode: None,
+            immutable_pattern: None,
+            stream_chunk_bytes: 64 * 1024,
+            max_generated_bytes: None,
+            read_only: false,
+            matrix_params: false,
+            trust_proxy: false,
+            require_length: false,
+            disable_demo_routes: false,
+            strict_authority: false,
+            directory_listing: false,
+            debug_errors: false,
+            server_timing: false,
+            events_count: 5,
+            echo_trailing_newline: false,
+            expect_continue: true,
+            nosniff: true,
+            options_unknown: OptionsUnknownPolicy::NotFound,
+            force_connection: None,
+            max_request_duration: None,
+            min_read_rate: None,
+            min_read_rate_window: Duration::from_secs(5),
+            retry_after: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            accept_backoff_max: Duration::from_secs(1),
+            max_requests: None,
+            max_conn_bytes: None,
+            max_conn_requests: None,
+            max_conn_lifetime: None,
+            max_conn_per_ip: None,
+            max_uri_bytes: 8 * 1024,
+            max_path_depth: 32,
+            max_header_value_bytes: 8 * 1024,
+            max_response_headers: 100,
+            max_response_header_bytes: 64 * 1024,
+            default_headers: Vec::new(),
+            require_upgrade: Vec::new(),
+            allow: Vec::new(),
+            admin_token: None,
+            log_file: None,
+            log_sample: 1.0,
+            log_format: "%h %m %U %s %b".to_owned(),
+            log_max_bytes: 10 * 1024 * 1024,
+            log_keep: 2,
+        }
+    }
+}
+
+fn next_value<T: std::str::FromStr>(args: &mut dyn Iterator<Item = String>) -> Option<T> {
+    args.next().and_then(|s| s.parse().ok())
+}
+
+/// Reads a positive number of (possibly fractional) seconds.
+fn next_duration(args: &mut dyn Iterator<Item = String>) -> Option<Duration> {
+    next_value::<f64>(args)
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+impl Configuration {
+    /// One line summarising where the server listens and what it has switched on.
+    fn startup_line(&self, address: &str) -> String {
+        // Every switch and optional behaviour, so the line tells what this process will do;
+        // plain size and time limits are left out.
+        let features = [
+            ("accept-rate", self.accept_rate.is_some()),
+            ("not-found-page", self.not_found_page.is_some()),
+            ("error-page", !self.error_pages.is_empty()),
+            ("template-root", self.template_root.is_some()),
+            ("unix-socket", self.unix_socket.is_some()),
+            ("robots", self.robots.is_some()),
+            ("spill", self.spill_threshold_bytes.is_some()),
+            ("upload-mode", self.upload_mode.is_some()),
+            ("immutable-pattern", self.immutable_pattern.is_some()),
+            ("read-only", self.read_only),
+            ("matrix-params", self.matrix_params),
+            ("trust-proxy", self.trust_proxy),
+            ("require-length", self.require_length),
+            ("disable-demo-routes", self.disable_demo_routes),
+            ("strict-authority", self.strict_authority),
+            ("directory-listing", self.directory_listing),
+            ("debug-errors", self.debug_errors),
+            ("server-timing", self.server_timing),
+            ("echo-trailing-newline", self.echo_trailing_newline),
+            ("expect-continue", self.expect_continue),
+            ("nosniff", self.nosniff),
+            ("force-connection", self.force_connection.is_some()),
+            ("min-read-rate", self.min_read_rate.is_some()),
+            ("max-requests", self.max_requests.is_some()),
+            ("log-file", self.log_file.is_some()),
+            ("log-sample", self.log_sample < 1.0),
+            ("default-header", !self.default_headers.is_empty()),
+            ("require-upgrade", !self.require_upgrade.is_empty()),
+            ("admin", self.admin_token.is_some()),
+            ("allow", !self.allow.is_empty()),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .join(",");
+        format!(
+            "listening address={} files_root={} threads=per-connection features={}",
+            address,
+            self.files_root.as_deref().unwrap_or("-"),
+            if features.is_empty() { "-" } else { &features },
+        )
+    }
+
+    /// Built-in defaults, overridden by `HTTP_*` environment variables, overridden in turn by
+    /// command line flags.
+    fn resolve() -> Configuration {
+        let configuration = Configuration::from_env(Configuration::default());
+        Configuration::from_args(&mut std::env::args(), configuration)
+    }
+
+    fn from_env(mut configuration: Configuration) -> Configuration {
+        if let Some(host) = std::env::var("HTTP_HOST").ok().filter(|h| !h.is_empty()) {
+            configuration.host = host;
+        }
+        if let Some(port) = std::env::var("HTTP_PORT").ok().and_then(|p| p.parse().ok()) {
+            configuration.port = port;
+        }
+        if let Some(root) = std::env::var("HTTP_FILES_ROOT")
+            .ok()
+            .filter(|r| !r.is_empty())
+        {
+            configuration.files_root = Some(root);
+        }
+        configuration
+    }
+
+    fn from_args(
+        args: &mut dyn Iterator<Item = String>,
+        mut configuration: Configuration,
+    ) -> Configuration {
+        args.next(); // skip first (program)
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--host" => configuration.host = args.next().unwrap_or(configuration.host),
+                "--port" => configuration.port = next_value(args).unwrap_or(configuration.port),
+                "--directory" => configuration.files_root = args.next(),
+                "--template-root" => configuration.template_root = args.next(),
+                "--unix-socket" => configuration.unix_socket = args.next(),
+                "--request-log-size" => {
+                    configuration.request_log_size =
+                        next_value(args).unwrap_or(configuration.request_log_size)
+                }
+                "--accept-rate" => configuration.accept_rate = next_value(args),
+                "--log-sample" => {
+                    configuration.log_sample = next_value(args)
+                        .filter(|rate: &f64| (0.0..=1.0).contains(rate))
+                        .unwrap_or(configuration.log_sample)
+                }
+                "--body-pool-size" => {
+                    configuration.body_pool_size =
+                        next_value(args).unwrap_or(configuration.body_pool_size)
+                }
+                "--body-pool-max-buffer-bytes" => {
+                    configuration.body_pool_max_buffer_bytes =
+                        next_value(args).unwrap_or(configuration.body_pool_max_buffer_bytes)
+                }
+                "--write-timeout" => configuration.write_timeout = next_duration(args),
+                "--max-request-duration" => {
+                    configuration.max_request_duration = next_duration(args)
+                }
+                "--retry-after" => {
+                    configuration.retry_after = next_value(args)
+                        .map(Duration::from_secs)
+                        .unwrap_or(configuration.retry_after)
+                }
+                "--min-read-rate" => configuration.min_read_rate = next_value(args),
+                "--min-read-rate-window" => {
+                    configuration.min_read_rate_window =
+                        next_duration(args).unwrap_or(configuration.min_read_rate_window)
+                }
+                "--accept-backoff-max" => {
+                    configuration.accept_backoff_max =
+                        next_duration(args).unwrap_or(configuration.accept_backoff_max)
+                }
+                "--shutdown-timeout" => {
+                    configuration.shutdown_timeout =
+                        next_duration(args).unwrap_or(configuration.shutdown_timeout)
+                }
+                "--max-requests" => configuration.max_requests = next_value(args),
+                "--max-conn-bytes" => configuration.max_conn_bytes = next_value(args),
+                "--max-conn-requests" => configuration.max_conn_requests = next_value(args),
+                "--max-conn-per-ip" => configuration.max_conn_per_ip = next_value(args),
+                "--max-conn-lifetime" => configuration.max_conn_lifetime = next_duration(args),
+                "--max-uri-bytes" => {
+                    configuration.max_uri_bytes =
+                        next_value(args).unwrap_or(configuration.max_uri_bytes)
+                }
+                "--max-path-depth" => {
+                    configuration.max_path_depth =
+                        next_value(args).unwrap_or(configuration.max_path_depth)
+                }
+                "--max-response-headers" => {
+                    configuration.max_response_headers =
+                        next_value(args).unwrap_or(configuration.max_response_headers)
+                }
+                "--max-response-header-bytes" => {
+                    configuration.max_response_header_bytes =
+                        next_value(args).unwrap_or(configuration.max_response_header_bytes)
+                }
+                "--max-header-value-bytes" => {
+                    configuration.max_header_value_bytes =
+                        next_value(args).unwrap_or(configuration.max_header_value_bytes)
+                }
+                "--log-file" => configuration.log_file = args.next(),
+                "--log-format" => {
+                    configuration.log_format = args.next().unwrap_or(configuration.log_format)
+                }
+                "--log-max-bytes" => {
+                    configuration.log_max_bytes =
+                        next_value(args).unwrap_or(configuration.log_max_bytes)
+                }
+                "--log-keep" => {
+                    configuration.log_keep = next_value(args).unwrap_or(configuration.log_keep)
+                }
+                "--default-header" => {
+                    if let Some((key, value)) = args
+                        .next()
+                        .as_deref()
+                        .and_then(|header| header.split_once(':'))
+                    {
+                        configuration
+                            .default_headers
+                            .push((key.trim().to_owned(), value.trim().to_owned()));
+                    }
+                }
+                "--admin-token" => {
+                    let token = args.next().unwrap_or_default();
+                    // An empty token would let anyone in who sends `Bearer ` and nothing else.
+                    if token.trim().is_empty() {
+                        eprintln!("--admin-token must not be empty");
+                        std::process::exit(2);
+                    }
+                    configuration.admin_token = Some(token);
+                }
+                "--allow" => {
+                    if let Some((method, prefix)) =
+                        args.next().as_deref().and_then(|rule| rule.split_once(':'))
+                    {
+                        configuration
+                            .allow
+                            .push((method.trim().to_ascii_uppercase(), prefix.trim().to_owned()));
+                    }
+                }
+                "--require-upgrade" => {
+                    if let Some((pattern, protocol)) = args
+                        .next()
+                        .as_deref()
+                        .and_then(|policy| policy.split_once('='))
+                    {
+                        configuration
+                            .require_upgrade
+                            .push((pattern.trim().to_owned(), protocol.trim().to_owned()));
+                    }
+                }
+                "--max-total-body-bytes" => configuration.max_total_body_bytes = next_value(args),
+                "--stream-chunk-bytes" => {
+                    configuration.stream_chunk_bytes = next_value(args)
+                        .filter(|bytes| *bytes > 0)
+                        .unwrap_or(configuration.stream_chunk_bytes)
+                }
+                "--max-generated-bytes" => configuration.max_generated_bytes = next_value(args),
+                "--immutable-pattern" => configuration.immutable_pattern = args.next(),
+                "--upload-mode" => {
+                    configuration.upload_mode = args
+                        .next()
+                        .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                        .filter(|mode| *mode <= 0o7777)
+                }
+                "--max-concurrent-requests" => {
+                    configuration.max_concurrent_requests = next_value(args)
+                }
+                "--max-concurrent-uploads" => {
+                    configuration.max_concurrent_uploads = next_value(args)
+                }
+                "--spill-threshold-bytes" => configuration.spill_threshold_bytes = next_value(args),
+                "--spill-dir" => {
+                    configuration.spill_dir =
+                        args.next().map_or(configuration.spill_dir, PathBuf::from)
+                }
+                "--max-body-bytes" => {
+                    configuration.max_body_bytes =
+                        next_value(args).unwrap_or(configuration.max_body_bytes)
+                }
+                "--read-only" => configuration.read_only = true,
+                "--matrix-params" => configuration.matrix_params = true,
+                "--trust-proxy" => configuration.trust_proxy = true,
+                "--require-length" => configuration.require_length = true,
+                "--disable-demo-routes" => configuration.disable_demo_routes = true,
+                "--strict-authority" => configuration.strict_authority = true,
+                "--directory-listing" => configuration.directory_listing = true,
+                "--debug-errors" => configuration.debug_errors = true,
+                "--server-timing" => configuration.server_timing = true,
+                "--events-count" => {
+                    configuration.events_count =
+                        next_value(args).unwrap_or(configuration.events_count)
+                }
+                "--echo-trailing-newline" => configuration.echo_trailing_newline = true,
+                "--no-expect-continue" => configuration.expect_continue = false,
+                "--nosniff" => configuration.nosniff = true,
+                "--no-nosniff" => configuration.nosniff = false,
+                "--force-connection" => configuration.force_connection = next_value(args),
+                "--options-unknown" => {
+                    configuration.options_unknown =
+                        next_value(args).unwrap_or(configuration.options_unknown)
+                }
+                "--robots" => {
+                    configuration.robots = args.next().and_then(|path| {
+                        std::fs::read(&path)
+                            .map_err(|e| println!("could not load robots file {}: {}", path, e))
+                            .ok()
+                    })
+                }
+                "--robots-disallow-all" => {
+                    configuration.robots = Some(b"User-agent: *\nDisallow: /\n".to_vec())
+                }
+                "--not-found-page" => {
+                    configuration.not_found_page = args.next().and_then(|path| {
+                        std::fs::read(&path)
+                            .map_err(|e| println!("could not load not-found page {}: {}", path, e))
+                            .ok()
+                    })
+                }
+                "--error-page" => {
+                    let page = args.next().and_then(|mapping| {
+                        let (code, path) = mapping.split_once('=')?;
+                        let code = code.trim().parse::<u16>().ok()?;
+                        std::fs::read(path)
+                            .map_err(|e| println!("could not load error page {}: {}", path, e))
+                            .ok()
+                            .map(|page| (code, page))
+                    });
+                    configuration.error_pages.extend(page);
+                }
+                _ => {}
+            }
+        }
+
+        configuration
+    }
+}
+
+use lazy_static::lazy_static;
+
+#[cfg(not(test))]
+lazy_static! {
+    static ref CONFIGURATION: Configuration = Configuration::resolve();
+}
+
+/// In tests, each thread reads the configuration it installed with `with_configuration`.
+#[cfg(test)]
+static CONFIGURATION: tests::InstalledConfiguration = tests::InstalledConfiguration;
+
+lazy_static! {
+    static ref LOG: LogSink = LogSink::new(&CONFIGURATION);
+    static ref SHUTDOWN: Shutdown = Shutdown::new();
+    static ref REQUEST_LOG: RequestLog = RequestLog::new(CONFIGURATION.request_log_size);
+    static ref BODY_BUDGET: BodyBudget = BodyBudget::new(CONFIGURATION.max_total_body_bytes);
+    static ref CONNECTIONS_PER_IP: ConnectionCounts =
+        ConnectionCounts::new(CONFIGURATION.max_conn_per_ip);
+    /// One unit per request being handled, for `--max-concurrent-requests`.
+    static ref REQUEST_SLOTS: Semaphore = Semaphore::new(CONFIGURATION.max_concurrent_requests);
+    /// One permit per file write in progress, for `--max-concurrent-uploads`.
+    static ref UPLOAD_SLOTS: Semaphore = Semaphore::new(CONFIGURATION.max_concurrent_uploads);
+    static ref BODY_BUFFERS: BufferPool = BufferPool::new(
+        CONFIGURATION.body_pool_size,
+        CONFIGURATION.body_pool_max_buffer_bytes
+    );
+    static ref STARTED: Instant = Instant::now();
+    /// Responses sent since startup, by status code.
+    static ref RESPONSES_BY_STATUS: Mutex<std::collections::BTreeMap<u16, usize>> =
+        Mutex::new(std::collections::BTreeMap::new());
+    /// Time from accepting a connection to the first byte of its first request.
+    static ref FIRST_BYTE_LATENCY: Histogram = Histogram::new(&[
+        0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0
+    ]);
+}
+
+/// Every exchange `handle_connection` completes, in test builds only.
+#[cfg(test)]
+static CAPTURED: Mutex<Vec<Exchange>> = Mutex::new(Vec::new());
+
+/// A log file that is rotated to `<path>.1`, `<path>.2`, ... once it grows past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: std::fs::File,
+    written: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *self = Self::open(self.path.clone(), self.max_bytes, self.keep)?;
+        self.file.set_len(0)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.written += len;
+        Ok(())
+    }
+}
+
+struct LogSink {
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl LogSink {
+    fn new(configuration: &Configuration) -> Self {
+        let file = configuration.log_file.as_ref().and_then(|path| {
+            RotatingFile::open(
+                PathBuf::from(path),
+                configuration.log_max_bytes,
+                configuration.log_keep,
+            )
+            .map_err(|e| println!("could not open log file {}: {}", path, e))
+            .ok()
+        });
+        Self {
+            file: file.map(Mutex::new),
+        }
+    }
+
+    fn write_line(&self, line: &str) {
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().expect("log file lock poisoned");
+                if let Err(e) = file.write_line(line) {
+                    println!("could not write to log file: {}: {}", e, line);
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+}
+
+/// Picks `true` with probability `rate`. Each std `RandomState` gets fresh random keys, which
+/// is unpredictable enough for log sampling without pulling in a random number crate.
+fn sampled(rate: f64) -> bool {
+    use std::hash::{BuildHasher, Hasher};
+    if rate >= 1.0 {
+        return true;
+    }
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random as f64 / u64::MAX as f64) < rate
+}
+
+/// Whether to write the access line for a response: server errors always, the rest at the
+/// `--log-sample` rate.
+fn logs_access(status: u16, rate: f64) -> bool {
+    status >= 500 || sampled(rate)
+}
+
+/// Requests handled since startup, for `--max-requests` and `/metrics`.
+static REQUESTS_HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts one more handled request; true once `max_requests` have been, time to shut down.
+fn count_request(handled: &AtomicUsize, max_requests: Option<usize>) -> bool {
+    let handled = handled.fetch_add(1, Ordering::SeqCst) + 1;
+    max_requests.is_some_and(|max_requests| handled >= max_requests)
+}
+/// Connections currently open, being served or idle between requests.
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/// Requests that carried a body, and the body bytes they carried, since startup.
+static REQUESTS_WITH_BODY: AtomicUsize = AtomicUsize::new(0);
+static REQUEST_BODY_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Durations counted into cumulative buckets, as a Prometheus histogram.
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum_micros: AtomicUsize,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum_micros: AtomicUsize::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as usize, Ordering::SeqCst);
+    }
+
+    fn to_prometheus(&self, name: &str) -> String {
+        let count = self.count.load(Ordering::SeqCst);
+        let buckets: String = self
+            .bounds
+            .iter()
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                format!(
+                    "{}_bucket{{le=\"{}\"}} {}\n",
+                    name,
+                    bound,
+                    bucket.load(Ordering::SeqCst)
+                )
+            })
+            .collect();
+        format!(
+            "# TYPE {name} histogram\n{buckets}{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}\n",
+            name = name,
+            buckets = buckets,
+            count = count,
+            sum = self.sum_micros.load(Ordering::SeqCst) as f64 / 1_000_000.0,
+        )
+    }
+}
+
+/// Counters in the Prometheus text exposition format.
+fn metrics_text() -> String {
+    let counters: String = [
+        ("http_requests_total", &REQUESTS_HANDLED),
+        ("http_requests_with_body_total", &REQUESTS_WITH_BODY),
+        ("http_request_body_bytes_total", &REQUEST_BODY_BYTES),
+    ]
+    .iter()
+    .map(|(name, counter)| {
+        format!(
+            "# TYPE {} counter\n{} {}\n",
+            name,
+            name,
+            counter.load(Ordering::SeqCst)
+        )
+    })
+    .collect();
+    let by_status: String = RESPONSES_BY_STATUS
+        .lock()
+        .expect("status counts lock poisoned")
+        .iter()
+        .map(|(status, count)| format!("http_responses_total{{status=\"{}\"}} {}\n", status, count))
+        .collect();
+    format!(
+        "{}# TYPE http_connections_active gauge\nhttp_connections_active {}\n\
+         # TYPE http_responses_total counter\n{}{}",
+        counters,
+        ACTIVE_CONNECTIONS.load(Ordering::SeqCst),
+        by_status,
+        FIRST_BYTE_LATENCY.to_prometheus("http_accept_to_first_byte_seconds")
+    )
+}
+
+/// Coordinates a graceful shutdown: stop taking work, let in-flight requests finish, then exit.
+struct Shutdown {
+    requested: AtomicBool,
+    in_flight: AtomicUsize,
+    /// Kept-alive connections waiting for their next request, by id, so a shutdown can wake
+    /// them instead of leaving them blocked in a read until the process exits.
+    idle: Mutex<HashMap<usize, Connection>>,
+    next_idle_id: AtomicUsize,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Self {
+            requested: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Mutex::new(HashMap::new()),
+            next_idle_id: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Counts a request as in flight until the returned guard drops, unwinding included.
+    fn track_request(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// Registers `stream` as idle until the returned guard drops. A connection that is already
+    /// shutting down is not registered and gets `None`, telling it to close now.
+    fn track_idle(&self, stream: &Connection) -> Option<Idle<'_>> {
+        let id = self.next_idle_id.fetch_add(1, Ordering::SeqCst);
+        if let Ok(clone) = stream.try_clone() {
+            self.idle
+                .lock()
+                .expect("idle connections lock poisoned")
+                .insert(id, clone);
+        }
+        // Checked after registering, so a shutdown starting in between still sees this one.
+        if self.is_requested() {
+            self.untrack_idle(id);
+            return None;
+        }
+        Some(Idle(self, id))
+    }
+
+    fn untrack_idle(&self, id: usize) {
+        self.idle
+            .lock()
+            .expect("idle connections lock poisoned")
+            .remove(&id);
+    }
+
+    /// Starts draining in the background; only the first call has any effect.
+    fn initiate(&'static self) {
+        if self.request() {
+            thread::spawn(move || self.drain_then_exit(CONFIGURATION.shutdown_timeout));
+        }
+    }
+
+    /// Marks shutdown as requested and closes idle connections; false if it already was.
+    fn request(&self) -> bool {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        log!("shutting down, waiting for in-flight requests");
+        // Ending the read side makes blocked reads return EOF, so idle connections close now.
+        for stream in self
+            .idle
+            .lock()
+            .expect("idle connections lock poisoned")
+            .values()
+        {
+            let _ = stream.shutdown_read();
+        }
+        true
+    }
+
+    /// Waits up to `timeout` for in-flight requests, then exits, dropping whatever remains.
+    fn drain_then_exit(&self, timeout: Duration) -> ! {
+        let remaining = self.drain(timeout);
+        if remaining > 0 {
+            log!(
+                "shutdown timeout reached, dropping {} in-flight requests",
+                remaining
+            );
+        }
+        std::process::exit(0)
+    }
+
+    /// Waits up to `timeout` for in-flight requests to finish; returns how many did not.
+    fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+struct InFlight<'a>(&'a Shutdown);
+
+struct Idle<'a>(&'a Shutdown, usize);
+
+impl Drop for Idle<'_> {
+    fn drop(&mut self) {
+        self.0.untrack_idle(self.1);
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Initiates a graceful shutdown on Ctrl-C or, on Unix, SIGTERM.
+fn spawn_signal_listener() {
+    thread::spawn(|| {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log!("could not listen for shutdown signals: {}", e);
+                return;
+            }
+        };
+        runtime.block_on(async {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::terminate()) {
+                    Ok(mut terminate) => {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => {}
+                            _ = terminate.recv() => {}
+                        }
+                    }
+                    Err(_) => {
+                        let _ = tokio::signal::ctrl_c().await;
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        });
+        SHUTDOWN.initiate();
+    });
+}
+
+fn main() {
+    #[cfg(not(test))]
+    lazy_static::initialize(&CONFIGURATION);
+    lazy_static::initialize(&STARTED);
+    spawn_signal_listener();
+
+    #[cfg(unix)]
+    if let Some(path) = CONFIGURATION.unix_socket.as_ref() {
+        let listener = bind_unix_socket(path).unwrap_or_else(|e| {
+            eprintln!("could not listen on --unix-socket {}: {}", path, e);
+            std::process::exit(2);
+        });
+        log!("{}", CONFIGURATION.startup_line(&format!("unix:{}", path)));
+        accept_loop(
+            listener
+                .incoming()
+                .map(|stream| stream.map(Connection::Unix)),
+        );
+        return;
+    }
+
+    let listener = TcpListener::bind((CONFIGURATION.host.as_str(), CONFIGURATION.port)).unwrap();
+    let address = listener.local_addr().map_or_else(
+        |_| format!("{}:{}", CONFIGURATION.host, CONFIGURATION.port),
+        |addr| addr.to_string(),
+    );
+    log!("{}", CONFIGURATION.startup_line(&address));
+    accept_loop(
+        listener
+            .incoming()
+            .map(|stream| stream.map(Connection::Tcp)),
+    );
+}
+
+/// Binds a Unix domain socket at `path`. A socket left behind by an earlier run is replaced,
+/// but anything else already there is left alone and refused.
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+fn accept_loop(incoming: impl Iterator<Item = std::io::Result<Connection>>) {
+    let mut accept_limiter = CONFIGURATION.accept_rate.map(AcceptRateLimiter::new);
+
+    // Accept errors such as running out of file descriptors tend to repeat until something
+    // else frees up, so back off instead of spinning on them.
+    const FIRST_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+    let mut accept_backoff = FIRST_ACCEPT_BACKOFF;
+    for stream in incoming {
+        if stream.is_ok() {
+            accept_backoff = FIRST_ACCEPT_BACKOFF;
+        }
+        match stream {
+            Ok(_) if SHUTDOWN.is_requested() => {}
+            Ok(stream) => {
+                let accepted = Instant::now();
+                if let Some(limiter) = accept_limiter.as_mut() {
+                    limiter.wait();
+                }
+                let _ = thread::spawn(move || handle_connection(&stream, accepted));
+            }
+            Err(e) => {
+                log!("error: {}, retrying in {:?}", e, accept_backoff);
+                thread::sleep(accept_backoff);
+                accept_backoff = (accept_backoff * 2).min(CONFIGURATION.accept_backoff_max);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::cell::Cell;
+
+    thread_local! {
+        static INSTALLED: Cell<Option<&'static Configuration>> = const { Cell::new(None) };
+    }
+
+    /// Stands in for the `CONFIGURATION` global: the defaults, unless this thread is inside
+    /// `with_configuration`.
+    pub(super) struct InstalledConfiguration;
+
+    impl std::ops::Deref for InstalledConfiguration {
+        type Target = Configuration;
+
+        fn deref(&self) -> &Configuration {
+            static DEFAULT: std::sync::OnceLock<Configuration> = std::sync::OnceLock::new();
+            INSTALLED
+                .with(Cell::get)
+                .unwrap_or_else(|| DEFAULT.get_or_init(Configuration::default))
+        }
+    }
+
+    /// Runs `run` with `configuration` in place of the defaults on this thread. Globals sized
+    /// from the configuration at first use, such as the semaphores, keep the defaults.
+    fn with_configuration<R>(configuration: Configuration, run: impl FnOnce() -> R) -> R {
+        let installed: &'static Configuration = Box::leak(Box::new(configuration));
+        let previous = INSTALLED.with(|cell| cell.replace(Some(installed)));
+        let result = run();
+        INSTALLED.with(|cell| cell.set(previous));
+        result
+    }
+
+    fn parse(raw: &str) -> Result<Request, RequestParseError> {
+        parse_request(&mut raw.as_bytes(), std::io::sink())
+    }
+
+    fn render(response: &Response, include_body: bool) -> String {
+        let mut written = Vec::new();
+        response
+            .write_to_stream(&mut written, include_body)
+            .expect("writing to a Vec cannot fail");
+        String::from_utf8(written).expect("test responses are UTF-8")
+    }
+
+    fn summary(path: &str) -> RequestSummary {
+        RequestSummary {
+            verb: "GET",
+            path: path.to_owned(),
+            status: 200,
+            bytes: 5,
+            duration: Duration::from_micros(7),
+        }
+    }
+
+    #[test]
+    fn parses_a_request_from_memory() {
+        let request = parse("GET /echo/hi?x=1 HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.verb.as_str(), "GET");
+        assert_eq!(request.path, "/echo/hi");
+        assert_eq!(request.query, vec![("x".to_owned(), "1".to_owned())]);
+        assert_eq!(request.header("Host"), Some("example.com"));
+        assert!(request.body.is_none());
+    }
+
+    #[test]
+    fn writes_status_line_headers_and_body() {
+        let response = Response::content_response(&status_codes::OK, "text/plain", b"hi".to_vec())
+            .with_header("X-Test", "1");
+        assert_eq!(
+            render(&response, true),
+            "HTTP/1.1 200 OK\r\nX-Test: 1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn request_log_keeps_the_newest_entries() {
+        let log = RequestLog::new(2);
+        log.record(summary("/a"));
+        log.record(summary("/b"));
+        log.record(summary("/c"));
+        assert_eq!(
+            log.to_json(),
+            "[{\"method\":\"GET\",\"path\":\"/b\",\"status\":200,\"bytes\":5,\"duration_us\":7},\
+             {\"method\":\"GET\",\"path\":\"/c\",\"status\":200,\"bytes\":5,\"duration_us\":7}]"
+        );
+    }
+
+    #[test]
+    fn request_log_of_size_zero_records_nothing() {
+        let log = RequestLog::new(0);
+        log.record(summary("/a"));
+        assert_eq!(log.to_json(), "[]");
+    }
+
+    #[test]
+    fn request_log_escapes_paths() {
+        let log = RequestLog::new(1);
+        log.record(summary("/\"quoted\"\\"));
+        assert!(log.to_json().contains("\"path\":\"/\\\"quoted\\\"\\\\\""));
+    }
+
+    #[test]
+    fn normalize_path_collapses_slashes_and_dot_segments() {
+        assert_eq!(normalize_path("/files//sub/./a").unwrap(), "/files/sub/a");
+        assert_eq!(normalize_path("//").unwrap(), "/");
+        assert_eq!(normalize_path("/a/b/").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/.").unwrap(), "/a/");
+        assert_eq!(normalize_path("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn normalize_path_refuses_parent_segments() {
+        assert!(matches!(
+            normalize_path("/files/../etc/passwd"),
+            Err(RequestParseError::PathTraversal)
+        ));
+        assert!(matches!(
+            parse("GET /a/.. HTTP/1.1\r\n\r\n"),
+            Err(RequestParseError::PathTraversal)
+        ));
+    }
+
+    fn tagged_response() -> Response<'static> {
+        Response::content_response(&status_codes::OK, "text/plain", b"hi".to_vec())
+            .with_header("ETag", "\"v1\"")
+            .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+    }
+
+    #[test]
+    fn etag_lists_compare_strongly_or_weakly() {
+        assert!(etag_list_matches("\"a\", \"v1\"", Some("\"v1\""), true));
+        assert!(etag_list_matches("*", Some("\"v1\""), true));
+        assert!(!etag_list_matches("*", None, false));
+        assert!(!etag_list_matches("W/\"v1\"", Some("\"v1\""), true));
+        assert!(!etag_list_matches("\"v1\"", Some("W/\"v1\""), true));
+        assert!(etag_list_matches("W/\"v1\"", Some("\"v1\""), false));
+        assert!(!etag_list_matches("\"v2\"", Some("\"v1\""), false));
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(951_782_400)), Some(951_782_400));
+    }
+
+    #[test]
+    fn obsolete_and_malformed_dates_are_not_parsed() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nIf-None-Match: \"v2\"\r\n\
+             If-Modified-Since: Mon, 07 Nov 1994 08:49:37 GMT\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            tagged_response()
+                .with_preconditions(&request)
+                .status_code
+                .code,
+            200
+        );
+    }
+
+    #[test]
+    fn if_match_failure_is_412() {
+        let request = parse("GET / HTTP/1.1\r\nIf-Match: \"v2\"\r\n\r\n").unwrap();
+        assert_eq!(
+            tagged_response()
+                .with_preconditions(&request)
+                .status_code
+                .code,
+            412
+        );
+    }
+
+    #[test]
+    fn conditional_headers_match_in_any_case() {
+        let request = parse("GET / HTTP/1.1\r\nif-none-match: \"v1\"\r\n\r\n").unwrap();
+        let response = tagged_response().with_preconditions(&request);
+        assert_eq!(response.status_code.code, 304);
+        assert!(response.content.is_none());
+
+        let request =
+            parse("GET / HTTP/1.1\r\nIF-MODIFIED-SINCE: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n")
+                .unwrap();
+        assert_eq!(
+            tagged_response()
+                .with_preconditions(&request)
+                .status_code
+                .code,
+            304
+        );
+    }
+
+    #[test]
+    fn negotiates_the_highest_quality_supported_language() {
+        assert_eq!(negotiate_language("fr-CH, fr;q=0.9, en;q=0.8"), "fr");
+        assert_eq!(negotiate_language("en;q=0.5, fr;q=0.7"), "fr");
+        assert_eq!(negotiate_language("de, FR;q=0.1"), "fr");
+        assert_eq!(negotiate_language("fr;q=0, de"), "en");
+        assert_eq!(negotiate_language("*"), "en");
+        assert_eq!(negotiate_language(""), "en");
+    }
+
+    #[test]
+    fn error_bodies_follow_a_lowercase_accept_language() {
+        let request = parse("GET / HTTP/1.1\r\naccept-language: fr\r\n\r\n").unwrap();
+        let response =
+            Response::not_found().with_localized_error_body(request.header("Accept-Language"));
+        assert!(render(&response, true).ends_with("Content-Language: fr\r\nVary: Accept-Language\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 11\r\n\r\nIntrouvable"));
+    }
+
+    #[test]
+    fn parses_rates_with_and_without_a_unit() {
+        let rate: Rate = "60/m".parse().unwrap();
+        assert_eq!((rate.count, rate.per), (60, Duration::from_secs(60)));
+        let rate: Rate = "1000".parse().unwrap();
+        assert_eq!((rate.count, rate.per), (1000, Duration::from_secs(1)));
+        let rate: Rate = "2/h".parse().unwrap();
+        assert_eq!(rate.per, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn rejects_zero_negative_and_unknown_rates() {
+        assert!("0/s".parse::<Rate>().is_err());
+        assert!("-1/s".parse::<Rate>().is_err());
+        assert!("10/d".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn accept_rate_limiter_spaces_out_slots() {
+        let limiter = AcceptRateLimiter::new("4/s".parse().unwrap());
+        assert_eq!(limiter.interval, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn splits_absolute_form_targets() {
+        assert_eq!(
+            split_absolute_form("http://example.com/a?b=c"),
+            (Some("example.com"), "/a?b=c")
+        );
+        assert_eq!(
+            split_absolute_form("HTTPS://example.com:8443"),
+            (Some("example.com:8443"), "")
+        );
+        assert_eq!(
+            split_absolute_form("http://example.com?q"),
+            (Some("example.com"), "?q")
+        );
+        assert_eq!(split_absolute_form("/plain"), (None, "/plain"));
+        assert_eq!(split_absolute_form("ftp://x/y"), (None, "ftp://x/y"));
+    }
+
+    #[test]
+    fn absolute_form_authority_replaces_host() {
+        let request = parse("GET http://example.com HTTP/1.1\r\nHost: other\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.header("host"), Some("example.com"));
+    }
+
+    /// A fresh directory under the system temp dir, named after the test using it.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("http-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn crc32_matches_the_reference_value() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32_update(crc32_update(0, b"1234"), b"56789"),
+            0xcbf4_3926
+        );
+    }
+
+    #[test]
+    fn zip_archive_collects_nested_files_in_order() {
+        let dir = temp_dir("zip-entries");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.txt"), b"bee").unwrap();
+        std::fs::write(dir.join("sub").join("a.txt"), b"hello").unwrap();
+        let archive = ZipArchive::of_directory(&dir).unwrap();
+        let names: Vec<&str> = archive.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["b.txt", "sub/a.txt"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zip_archive_writes_exactly_its_announced_length() {
+        let dir = temp_dir("zip-write");
+        std::fs::write(dir.join("a.txt"), b"123456789").unwrap();
+        let archive = ZipArchive::of_directory(&dir).unwrap();
+        let mut written = Vec::new();
+        archive.write_to(&mut written).unwrap();
+        assert_eq!(written.len(), archive.len());
+        assert_eq!(&written[..4], b"PK\x03\x04");
+        // The data descriptor follows the header, name and data.
+        let descriptor = &written[30 + 5 + 9..][..16];
+        assert_eq!(&descriptor[..4], b"PK\x07\x08");
+        assert_eq!(&descriptor[4..8], &0xcbf4_3926u32.to_le_bytes());
+        let end = &written[written.len() - ZipArchive::END_LEN..];
+        assert_eq!(&end[..4], b"PK\x05\x06");
+        assert_eq!(&end[8..10], &1u16.to_le_bytes());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zip_archive_of_a_missing_directory_is_an_error() {
+        let missing = std::env::temp_dir().join("http-test-zip-missing-for-sure");
+        assert!(ZipArchive::of_directory(&missing).is_err());
+    }
+
+    #[test]
+    fn access_line_expands_every_directive() {
+        assert_eq!(
+            summary("/a b").access_line("%h %m %U %s %b %D", "10.0.0.1"),
+            "10.0.0.1 GET /a b 200 5 7"
+        );
+    }
+
+    #[test]
+    fn access_line_copies_unknown_directives_through() {
+        assert_eq!(
+            summary("/").access_line("100%% %x done %", "-"),
+            "100% %x done %"
+        );
+    }
+
+    #[test]
+    fn rejects_both_content_length_and_transfer_encoding() {
+        assert!(matches!(
+            parse(
+                "POST /post-echo HTTP/1.1\r\nContent-Length: 3\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"
+            ),
+            Err(RequestParseError::ConflictingFraming)
+        ));
+    }
+
+    #[test]
+    fn rejects_repeated_framing_headers_in_any_case() {
+        for raw in [
+            "POST /post-echo HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 6\r\n\r\nabcdef",
+            "POST /post-echo HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc",
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+             TRANSFER-ENCODING: chunked\r\n\r\n0\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse(raw), Err(RequestParseError::DuplicateFraming)),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn header_names_are_stored_lowercased() {
+        let request = parse("GET / HTTP/1.1\r\nUSER-AGENT: curl\r\n\r\n").unwrap();
+        assert_eq!(
+            request.headers.get("user-agent").map(String::as_str),
+            Some("curl")
+        );
+        assert_eq!(request.header("User-Agent"), Some("curl"));
+    }
+
+    #[test]
+    fn rejects_duplicate_host_headers() {
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n"),
+            Err(RequestParseError::DuplicateHost)
+        ));
+    }
+
+    #[test]
+    fn rejects_an_over_long_target_with_414() {
+        let target = format!("/{}", "a".repeat(CONFIGURATION.max_uri_bytes));
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        assert!(matches!(parse(&raw), Err(RequestParseError::UriTooLong)));
+        let query = format!("/?q={}", "a".repeat(CONFIGURATION.max_uri_bytes - 3));
+        assert!(matches!(
+            parse(&format!("GET {} HTTP/1.1\r\n\r\n", query)),
+            Err(RequestParseError::UriTooLong)
+        ));
+        assert_eq!(RequestParseError::UriTooLong.status_code().code, 414);
+
+        let longest = format!("/{}", "a".repeat(CONFIGURATION.max_uri_bytes - 1));
+        assert!(parse(&format!("OPTIONS {} HTTP/1.1\r\n\r\n", longest)).is_ok());
+    }
+
+    #[test]
+    fn stops_reading_a_start_line_without_an_end() {
+        // The line never ends; only the limit stops it from being read in full.
+        let mut endless = std::io::repeat(b'a').take(u64::MAX);
+        let mut reader = BufReader::new(&mut endless);
+        assert!(matches!(
+            parse_request(&mut reader, std::io::sink()),
+            Err(RequestParseError::UriTooLong)
+        ));
+    }
+
+    #[test]
+    fn rejects_one_oversized_header_value_with_431() {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "c".repeat(CONFIGURATION.max_header_value_bytes + 1)
+        );
+        assert!(matches!(
+            parse(&raw),
+            Err(RequestParseError::HeaderValueTooLarge)
+        ));
+        assert_eq!(
+            RequestParseError::HeaderValueTooLarge.status_code().code,
+            431
+        );
+        let raw = format!(
+            "GET / HTTP/1.1\r\nCookie: {}\r\n\r\n",
+            "c".repeat(CONFIGURATION.max_header_value_bytes)
+        );
+        assert!(parse(&raw).is_ok());
+    }
+
+    #[test]
+    fn limits_header_lines_without_a_separator() {
+        let mut endless = b"GET / HTTP/1.1\r\nX".chain(std::io::repeat(b'x').take(u64::MAX));
+        let mut reader = BufReader::new(&mut endless);
+        assert!(matches!(
+            parse_request(&mut reader, std::io::sink()),
+            Err(RequestParseError::HeaderValueTooLarge)
+        ));
+    }
+
+    fn body_of(request: &Request) -> Vec<u8> {
+        let mut body = Vec::new();
+        request
+            .body
+            .as_ref()
+            .expect("request should have a body")
+            .reader()
+            .unwrap()
+            .read_to_end(&mut body)
+            .unwrap();
+        body
+    }
+
+    #[test]
+    fn skips_chunk_extensions() {
+        let request = parse(
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3;name=value\r\nabc\r\n2;flag\r\nde\r\n0;last\r\nTrailer: x\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(body_of(&request), b"abcde");
+    }
+
+    #[test]
+    fn lengths_and_chunk_sizes_must_be_bare_digits() {
+        for raw in [
+            "POST /post-echo HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc",
+            "POST /post-echo HTTP/1.1\r\nContent-Length: -0\r\n\r\n",
+            "POST /post-echo HTTP/1.1\r\nContent-Length: 3 3\r\n\r\nabc",
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n",
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0x3\r\nabc\r\n0\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse(raw), Err(RequestParseError::InvalidContentLength)),
+                "{:?}",
+                raw
+            );
+        }
+        assert_eq!(parse_length("1a", 16), Some(26));
+        assert_eq!(parse_length("", 10), None);
+    }
+
+    #[test]
+    fn limits_chunk_size_lines() {
+        let raw = format!(
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;{}\r\nabc\r\n0\r\n\r\n",
+            "x".repeat(2000)
+        );
+        assert!(matches!(
+            parse(&raw),
+            Err(RequestParseError::InvalidStructure)
+        ));
+    }
+
+    /// Sends `raw` over a real connection served by `handle_connection`, returning everything
+    /// written back until the server closes it. The client stops sending after `raw`, so a
+    /// server that keeps the connection alive closes it once it reads the end.
+    fn exchange(raw: &[u8]) -> String {
+        exchange_with(Configuration::default(), raw)
+    }
+
+    /// `exchange`, served under `configuration`.
+    fn exchange_with(configuration: Configuration, raw: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            with_configuration(configuration, || {
+                handle_connection(&Connection::Tcp(stream), Instant::now())
+            });
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(raw).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn head_on_a_404_describes_the_error_body_without_sending_it() {
+        assert_eq!(
+            exchange(b"HEAD /nope HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 404 Not Found\r\nContent-Language: en\r\nVary: Accept-Language\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 9\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn head_on_an_error_leaves_the_next_pipelined_request_intact() {
+        let response = exchange(b"HEAD /nope HTTP/1.1\r\n\r\nGET /echo/ok HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\nok"), "{:?}", response);
+    }
+
+    #[test]
+    fn rejects_overflowing_content_lengths_as_malformed() {
+        let raw = "POST /post-echo HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert!(matches!(
+            parse(raw),
+            Err(RequestParseError::InvalidContentLength)
+        ));
+        assert!(exchange(raw.as_bytes()).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn rejects_huge_content_lengths_before_reading() {
+        let raw = format!(
+            "POST /post-echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        assert!(matches!(parse(&raw), Err(RequestParseError::BodyTooLarge)));
+        let raw = format!(
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            usize::MAX
+        );
+        assert!(matches!(parse(&raw), Err(RequestParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn options_responses_send_a_zero_content_length() {
+        assert_eq!(
+            exchange(b"OPTIONS /echo/x HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 200 OK\r\nAllow: GET, HEAD, OPTIONS\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn too_large_bodies_get_413_and_close_the_connection() {
+        let raw = format!(
+            "POST /post-echo HTTP/1.1\r\nContent-Length: {}\r\n\r\nGET /echo/next HTTP/1.1\r\n\r\n",
+            CONFIGURATION.max_body_bytes + 1
+        );
+        let response = exchange(raw.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(!response.contains("next"), "{:?}", response);
+    }
+
+    #[test]
+    fn unsupported_range_units_get_the_full_response() {
+        let response = exchange(b"GET /echo/abcdef HTTP/1.1\r\nRange: items=0-1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Content-Range"));
+        assert!(
+            response.ends_with("Content-Length: 6\r\n\r\nabcdef"),
+            "{:?}",
+            response
+        );
+    }
+
+    #[test]
+    fn chunked_post_echo_reports_the_decoded_length() {
+        let response = exchange(
+            b"POST /post-echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n4\r\ndefg\r\n0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(
+            response.ends_with("Content-Length: 7\r\n\r\nabcdefg"),
+            "{:?}",
+            response
+        );
+    }
+
+    /// The headers of a response, without its status line or body.
+    fn head_of(response: &str) -> &str {
+        let headers = response
+            .split_once("\r\n\r\n")
+            .map_or(response, |(head, _)| head);
+        headers.split_once("\r\n").map_or("", |(_, rest)| rest)
+    }
+
+    #[test]
+    fn head_on_generated_routes_matches_get_without_a_body() {
+        for path in ["/echo/hello", "/time"] {
+            let get = exchange(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes());
+            let head = exchange(format!("HEAD {} HTTP/1.1\r\n\r\n", path).as_bytes());
+            assert!(head.ends_with("\r\n\r\n"), "{:?}", head);
+            assert!(head_of(&head).contains("Content-Length: "), "{:?}", head);
+            if path == "/time" {
+                // The time may tick between the two requests, but not the length of its text.
+                let length = |response: &str| {
+                    head_of(response)
+                        .lines()
+                        .find(|line| line.starts_with("Content-Length"))
+                        .map(str::to_owned)
+                };
+                assert_eq!(length(&head), length(&get));
+            } else {
+                assert_eq!(head_of(&head), head_of(&get));
+            }
+        }
+    }
+
+    #[test]
+    fn uploads_are_spilled_to_disk_whatever_their_size() {
+        let request = parse(
+            "POST /files/up HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        let Some(RequestBody::Spilled(spilled)) = &request.body else {
+            panic!("upload body should be spilled: {:?}", request.body);
+        };
+        assert_eq!(spilled.len, 6);
+        let path = spilled.path.clone();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+        drop(request);
+        assert!(!path.exists());
+
+        let request = parse("POST /files/up HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert!(matches!(request.body, Some(RequestBody::Spilled(_))));
+        assert_eq!(body_of(&request), b"hi");
+    }
+
+    #[test]
+    fn writes_uploads_atomically_into_place() {
+        let dir = temp_dir("upload");
+        let path = dir.join("file");
+        write_file_atomically(&path, &mut &b"first"[..]).unwrap();
+        write_file_atomically(&path, &mut &b"second"[..]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["file"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn answers_expect_100_continue_before_reading_the_body() {
+        let mut interim = Vec::new();
+        let raw = "POST /files/up HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi";
+        let request = parse_request(&mut raw.as_bytes(), &mut interim).unwrap();
+        assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(body_of(&request), b"hi");
+
+        let mut interim = Vec::new();
+        let raw = format!(
+            "POST /files/up HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+            CONFIGURATION.max_body_bytes + 1
+        );
+        assert!(matches!(
+            parse_request(&mut raw.as_bytes(), &mut interim),
+            Err(RequestParseError::BodyTooLarge)
+        ));
+        assert!(interim.is_empty());
+    }
+
+    #[test]
+    fn caps_response_headers_but_keeps_connection_and_content_headers() {
+        let mut response =
+            Response::content_response(&status_codes::OK, "text/plain", b"hi".to_vec());
+        for n in 0..CONFIGURATION.max_response_headers + 50 {
+            response = response.with_header(&format!("X-Extra-{}", n), "1");
+        }
+        let response = response
+            .with_header("Content-Language", "en")
+            .with_header("Connection", "close");
+        let written = render(&response, true);
+        let extras = written
+            .lines()
+            .filter(|line| line.starts_with("X-Extra-"))
+            .count();
+        assert_eq!(extras, CONFIGURATION.max_response_headers);
+        assert!(written.contains("\r\nContent-Language: en\r\n"));
+        assert!(written.contains("\r\nConnection: close\r\n"));
+        assert!(written.ends_with("Content-Length: 2\r\n\r\nhi"));
+    }
+
+    #[test]
+    fn allow_prefixes_match_whole_segments() {
+        assert!(is_under("/static", "/static"));
+        assert!(is_under("/static/app.js", "/static"));
+        assert!(is_under("/static/app.js", "/static/"));
+        assert!(is_under("/anything", "/"));
+        assert!(!is_under("/static-secrets", "/static"));
+        assert!(!is_under("/stat", "/static"));
+    }
+
+    #[test]
+    fn compares_tokens_exactly() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+
+    #[test]
+    fn admin_dashboard_requires_the_bearer_token() {
+        let authorized =
+            parse("GET /admin/dashboard HTTP/1.1\r\nauthorization: Bearer tok\r\n\r\n");
+        assert_eq!(
+            admin_dashboard(&authorized.unwrap(), "tok")
+                .status_code
+                .code,
+            200
+        );
+        for raw in [
+            "GET /admin/dashboard HTTP/1.1\r\n\r\n",
+            "GET /admin/dashboard HTTP/1.1\r\nAuthorization: Bearer \r\n\r\n",
+            "GET /admin/dashboard HTTP/1.1\r\nAuthorization: Bearer tok2\r\n\r\n",
+            "GET /admin/dashboard HTTP/1.1\r\nAuthorization: Basic tok\r\n\r\n",
+        ] {
+            let response = admin_dashboard(&parse(raw).unwrap(), "tok");
+            assert_eq!(response.status_code.code, 401, "{:?}", raw);
+        }
+    }
+
+    /// The captured exchange for the one request made to `path`.
+    fn captured(path: &str) -> Exchange {
+        let mut captured = CAPTURED.lock().unwrap();
+        let index = captured
+            .iter()
+            .position(|exchange| exchange.path == path)
+            .expect("request should have been captured");
+        captured.remove(index)
+    }
+
+    #[test]
+    fn captures_each_request_with_its_response() {
+        exchange(b"GET /echo/captured HTTP/1.1\r\nX-Probe: 1\r\n\r\n");
+        let exchange = captured("/echo/captured");
+        assert_eq!(exchange.verb, "GET");
+        assert_eq!(
+            exchange.headers.get("x-probe").map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(exchange.status, 200);
+        assert_eq!(exchange.body_len, "captured".len());
+        assert!(exchange
+            .response_headers
+            .iter()
+            .any(|(key, _)| key == "ETag"));
+    }
+
+    #[test]
+    fn startup_line_lists_enabled_features() {
+        let configuration = Configuration {
+            trust_proxy: true,
+            directory_listing: true,
+            allow: vec![("GET".to_owned(), "/".to_owned())],
+            admin_token: Some("secret".to_owned()),
+            expect_continue: false,
+            nosniff: false,
+            ..Configuration::default()
+        };
+        let line = configuration.startup_line("127.0.0.1:4221");
+        assert_eq!(
+            line,
+            "listening address=127.0.0.1:4221 files_root=- threads=per-connection \
+             features=trust-proxy,directory-listing,admin,allow"
+        );
+        assert!(!line.contains("secret"));
+    }
+
+    #[test]
+    fn startup_line_without_features_says_so() {
+        let configuration = Configuration {
+            expect_continue: false,
+            nosniff: false,
+            ..Configuration::default()
+        };
+        assert!(configuration.startup_line("x").ends_with(" features=-"));
+    }
+
+    fn context() -> RequestContext {
+        let started = Instant::now();
+        RequestContext {
+            started,
+            parsed: started,
+            deadline: None,
+            previous_write: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn server_timing_reports_parse_handle_and_write() {
+        let timing = context().server_timing();
+        let names: Vec<&str> = timing
+            .split(", ")
+            .map(|metric| metric.split(';').next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["parse", "handle", "write"]);
+        assert!(timing.split(", ").all(|metric| metric.contains(";dur=")));
+        assert!(timing.contains("write;dur=1.500;"), "{}", timing);
+    }
+
+    #[test]
+    fn timing_json_reports_parse_handle_and_write() {
+        let json = context().timing_json();
+        assert!(
+            json.starts_with("{\"parse_us\":0,\"handle_us\":"),
+            "{}",
+            json
+        );
+        assert!(json.ends_with(",\"write_us\":1500}"), "{}", json);
+    }
+
+    #[test]
+    fn server_timing_write_is_zero_until_a_response_is_written() {
+        let configuration = Configuration {
+            server_timing: true,
+            ..Configuration::default()
+        };
+        let response = exchange_with(
+            configuration,
+            b"GET /debug/timing HTTP/1.1\r\n\r\nGET /debug/timing HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let timings: Vec<&str> = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("Server-Timing: "))
+            .collect();
+        assert_eq!(timings.len(), 2, "{}", response);
+        assert!(timings[0].contains("write;dur=0.000;"), "{}", timings[0]);
+        assert!(timings[1].contains("write;dur="), "{}", timings[1]);
+        assert_eq!(response.matches("\"write_us\":").count(), 2);
+    }
+
+    #[test]
+    fn semaphore_permits_return_on_drop_and_unwind() {
+        let slots = Semaphore::new(Some(1));
+        let permit = slots.try_acquire();
+        assert!(permit.is_some());
+        assert!(slots.try_acquire().is_none());
+        drop(permit);
+        assert!(slots.try_acquire().is_some());
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _permit = slots.try_acquire();
+            panic!("handler panicked");
+        }));
+        assert!(result.is_err());
+        assert!(slots.try_acquire().is_some());
+    }
+
+    #[test]
+    fn unlimited_semaphore_always_grants() {
+        let slots = Semaphore::new(None);
+        let permits: Vec<_> = (0..100).map(|_| slots.try_acquire()).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn body_budget_refuses_past_its_limit_until_released() {
+        let budget = BodyBudget::new(Some(10));
+        assert!(budget.try_reserve(6));
+        assert!(!budget.try_reserve(5));
+        assert!(!budget.try_reserve(usize::MAX));
+        budget.release(6);
+        assert!(budget.try_reserve(10));
+    }
+
+    #[test]
+    fn buffered_bodies_grow_as_chunks_arrive() {
+        let mut body = BufferedBody::reserve(2).unwrap();
+        body.0.copy_from_slice(b"ab");
+        body.grow(1).unwrap().copy_from_slice(b"c");
+        assert_eq!(&body[..], b"abc");
+        assert!(BufferedBody::empty().is_empty());
+    }
+
+    #[test]
+    fn internal_errors_keep_details_out_of_the_body_by_default() {
+        let error = std::io::Error::other("/secret/path does not fit in a ZIP archive");
+        let written = render(&Response::internal_error(&error), true);
+        assert_eq!(
+            written,
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_over_a_unix_socket() {
+        use std::os::unix::net::UnixStream;
+        let path = temp_dir("unix-socket").join("server.sock");
+        let path = path.to_str().unwrap().to_owned();
+        let listener = bind_unix_socket(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&Connection::Unix(stream), Instant::now());
+        });
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"GET /echo/unix HTTP/1.1\r\n\r\n")
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nunix"), "{:?}", response);
+
+        // A stale socket from an earlier run is replaced.
+        drop(bind_unix_socket(&path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_to_replace_a_file_with_a_unix_socket() {
+        let path = temp_dir("unix-socket-file").join("important");
+        std::fs::write(&path, b"important").unwrap();
+        assert!(bind_unix_socket(path.to_str().unwrap()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"important");
+    }
+
+    /// A command line as `from_args` sees it, program name first.
+    fn args(args: &[&str]) -> std::vec::IntoIter<String> {
+        let mut line = vec!["server".to_owned()];
+        line.extend(args.iter().map(|arg| arg.to_string()));
+        line.into_iter()
+    }
+
+    #[test]
+    fn environment_is_layered_under_flags() {
+        // The only test that touches these variables, so parallel tests cannot see them.
+        std::env::set_var("HTTP_HOST", "0.0.0.0");
+        std::env::set_var("HTTP_PORT", "8080");
+        std::env::set_var("HTTP_FILES_ROOT", "/srv/files");
+        let from_env = Configuration::from_env(Configuration::default());
+        assert_eq!(from_env.host, "0.0.0.0");
+        assert_eq!(from_env.port, 8080);
+        assert_eq!(from_env.files_root.as_deref(), Some("/srv/files"));
+
+        let layered = Configuration::from_args(&mut args(&["--port", "9000"]), from_env);
+        assert_eq!(layered.port, 9000);
+        assert_eq!(layered.host, "0.0.0.0");
+
+        // The test binary's own arguments set none of these.
+        let resolved = Configuration::resolve();
+        assert_eq!((resolved.host.as_str(), resolved.port), ("0.0.0.0", 8080));
+
+        std::env::set_var("HTTP_PORT", "not a port");
+        std::env::set_var("HTTP_HOST", "");
+        let ignored = Configuration::from_env(Configuration::default());
+        assert_eq!((ignored.host.as_str(), ignored.port), ("127.0.0.1", 4221));
+        for name in ["HTTP_HOST", "HTTP_PORT", "HTTP_FILES_ROOT"] {
+            std::env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn ignores_expect_100_continue_from_http_1_0_clients() {
+        let mut interim = Vec::new();
+        let raw = "POST /files/up HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi";
+        let request = parse_request(&mut raw.as_bytes(), &mut interim).unwrap();
+        assert!(interim.is_empty());
+        assert_eq!(body_of(&request), b"hi");
+    }
+
+    /// The defaults with `--directory` pointing at a fresh temporary directory.
+    fn files_configuration(name: &str) -> (Configuration, PathBuf) {
+        let dir = temp_dir(name);
+        let configuration = Configuration {
+            files_root: Some(dir.to_str().unwrap().to_owned()),
+            ..Configuration::default()
+        };
+        (configuration, dir)
+    }
+
+    #[test]
+    fn refuses_uploads_without_a_file_name() {
+        let (configuration, dir) = files_configuration("upload-no-name");
+        for path in ["/files/", "/files/sub/"] {
+            let raw = format!("POST {} HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi", path);
+            let response = exchange_with(configuration.clone(), raw.as_bytes());
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{:?}",
+                response
+            );
+        }
+        let parent = dir.parent().unwrap();
+        let root_name = dir.file_name().unwrap().to_string_lossy().into_owned();
+        let stray = std::fs::read_dir(parent)
+            .unwrap()
+            .filter_map(Result::ok)
+            .any(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&format!(".{}", root_name))
+            });
+        assert!(
+            !stray,
+            "a temporary file was written outside the files root"
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uploads_land_in_the_files_root() {
+        let (configuration, dir) = files_configuration("upload-ok");
+        let response = exchange_with(
+            configuration,
+            b"POST /files/a.txt HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{:?}",
+            response
+        );
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"hi");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The defaults with `--directory-listing` over a fresh files root.
+    fn listing_configuration(name: &str) -> (Configuration, PathBuf) {
+        let (configuration, dir) = files_configuration(name);
+        let configuration = Configuration {
+            directory_listing: true,
+            ..configuration
+        };
+        (configuration, dir)
+    }
+
+    #[test]
+    fn directory_listing_links_are_absolute_and_encoded() {
+        let (configuration, dir) = listing_configuration("listing-links");
+        std::fs::create_dir_all(dir.join("sub").join("inner")).unwrap();
+        std::fs::write(dir.join("sub").join("a.txt"), b"a").unwrap();
+        std::fs::write(dir.join("sub").join("we#ird?.txt"), b"w").unwrap();
+        for path in ["/files/sub", "/files/sub/"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let response = exchange_with(configuration.clone(), raw.as_bytes());
+            assert!(
+                response.contains("<a href=\"/files/sub/a.txt\">a.txt</a>"),
+                "{}",
+                response
+            );
+            assert!(response.contains("<a href=\"/files/sub/inner/\">inner/</a>"));
+            assert!(response.contains("<a href=\"/files/sub/we%23ird%3F.txt\">we#ird?.txt</a>"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn percent_encodes_path_segments() {
+        assert_eq!(percent_encode("a b/c#d"), "a%20b%2Fc%23d");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+        assert_eq!(
+            percent_decode(&percent_encode("we#ird?.txt")),
+            "we#ird?.txt"
+        );
+    }
+
+    #[test]
+    fn skipped_bodies_are_counted_but_not_buffered() {
+        let body = "x".repeat(200_000);
+        let before = REQUEST_BODY_BYTES.load(Ordering::SeqCst);
+        let request = parse(&format!(
+            "POST /echo/x HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ))
+        .unwrap();
+        assert!(request.body.is_none());
+        assert!(REQUEST_BODY_BYTES.load(Ordering::SeqCst) - before >= body.len());
+
+        let before = REQUEST_BODY_BYTES.load(Ordering::SeqCst);
+        let request =
+            parse("POST /echo/x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n3\r\nabc\r\n0\r\n\r\n")
+                .unwrap();
+        assert!(request.body.is_none());
+        assert!(REQUEST_BODY_BYTES.load(Ordering::SeqCst) - before >= 8);
+
+        let response = exchange(
+            format!(
+                "POST /echo/x HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    }
+
+    #[test]
+    fn ct_query_overrides_the_type_of_served_files_only() {
+        let (configuration, dir) = files_configuration("ct-override");
+        std::fs::write(dir.join("data.bin"), b"{}").unwrap();
+        let configuration = Configuration {
+            not_found_page: Some(b"<h1>missing</h1>".to_vec()),
+            ..configuration
+        };
+
+        let response = exchange_with(
+            configuration.clone(),
+            b"GET /files/data.bin?ct=application/json HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+
+        let response = exchange_with(
+            configuration.clone(),
+            b"GET /files/data.bin?ct=not-a-type HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+
+        let response = exchange_with(
+            configuration,
+            b"GET /files/missing.bin?ct=application/json HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nContent-Type: text/html\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn user_agent_without_the_header_is_a_bad_request() {
+        let response = exchange(b"GET /user-agent HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+
+        let response = exchange(
+            b"GET /user-agent HTTP/1.1\r\nUser-Agent: probe/1.0\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nprobe/1.0"), "{}", response);
+    }
+
+    #[test]
+    fn every_registered_status_code_has_its_reason_phrase() {
+        // The IANA HTTP Status Code Registry, leaving out the unused 306 and 418.
+        let registry = [
+            (100, "Continue"),
+            (101, "Switching Protocols"),
+            (102, "Processing"),
+            (103, "Early Hints"),
+            (200, "OK"),
+            (201, "Created"),
+            (202, "Accepted"),
+            (203, "Non-Authoritative Information"),
+            (204, "No Content"),
+            (205, "Reset Content"),
+            (206, "Partial Content"),
+            (207, "Multi-Status"),
+            (208, "Already Reported"),
+            (226, "IM Used"),
+            (300, "Multiple Choices"),
+            (301, "Moved Permanently"),
+            (302, "Found"),
+            (303, "See Other"),
+            (304, "Not Modified"),
+            (305, "Use Proxy"),
+            (307, "Temporary Redirect"),
+            (308, "Permanent Redirect"),
+            (400, "Bad Request"),
+            (401, "Unauthorized"),
+            (402, "Payment Required"),
+            (403, "Forbidden"),
+            (404, "Not Found"),
+            (405, "Method Not Allowed"),
+            (406, "Not Acceptable"),
+            (407, "Proxy Authentication Required"),
+            (408, "Request Timeout"),
+            (409, "Conflict"),
+            (410, "Gone"),
+            (411, "Length Required"),
+            (412, "Precondition Failed"),
+            (413, "Content Too Large"),
+            (414, "URI Too Long"),
+            (415, "Unsupported Media Type"),
+            (416, "Range Not Satisfiable"),
+            (417, "Expectation Failed"),
+            (421, "Misdirected Request"),
+            (422, "Unprocessable Content"),
+            (423, "Locked"),
+            (424, "Failed Dependency"),
+            (425, "Too Early"),
+            (426, "Upgrade Required"),
+            (428, "Precondition Required"),
+            (429, "Too Many Requests"),
+            (431, "Request Header Fields Too Large"),
+            (451, "Unavailable For Legal Reasons"),
+            (500, "Internal Server Error"),
+            (501, "Not Implemented"),
+            (502, "Bad Gateway"),
+            (503, "Service Unavailable"),
+            (504, "Gateway Timeout"),
+            (505, "HTTP Version Not Supported"),
+            (506, "Variant Also Negotiates"),
+            (507, "Insufficient Storage"),
+            (508, "Loop Detected"),
+            (510, "Not Extended"),
+            (511, "Network Authentication Required"),
+        ];
+        for (code, phrase) in registry {
+            assert_eq!(status_codes::reason_phrase(code), phrase, "{}", code);
+        }
+        for unregistered in [99, 306, 418, 509, 600] {
+            assert_eq!(status_codes::reason_phrase(unregistered), "Unknown");
+        }
+
+        let named = [
+            &status_codes::OK,
+            &status_codes::CREATED,
+            &status_codes::NO_CONTENT,
+            &status_codes::NOT_MODIFIED,
+            &status_codes::BAD_REQUEST,
+            &status_codes::UNAUTHORIZED,
+            &status_codes::FORBIDDEN,
+            &status_codes::NOT_FOUND,
+            &status_codes::METHOD_NOT_ALLOWED,
+            &status_codes::REQUEST_TIMEOUT,
+            &status_codes::LENGTH_REQUIRED,
+            &status_codes::PRECONDITION_FAILED,
+            &status_codes::CONTENT_TOO_LARGE,
+            &status_codes::URI_TOO_LONG,
+            &status_codes::UPGRADE_REQUIRED,
+            &status_codes::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            &status_codes::INTERNAL_SERVER_ERROR,
+            &status_codes::SERVICE_UNAVAILABLE,
+            &status_codes::HTTP_VERSION_NOT_SUPPORTED,
+        ];
+        for status_code in named {
+            assert!(
+                registry.contains(&(status_code.code, status_code.status)),
+                "{} {}",
+                status_code.code,
+                status_code.status
+            );
+        }
+    }
+
+    #[test]
+    fn in_flight_count_returns_to_zero_after_a_handler_panics() {
+        let shutdown = Shutdown::new();
+        let panicked = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let _in_flight = shutdown.track_request();
+                    assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 1);
+                    panic!("handler failed");
+                })
+                .join()
+        });
+        assert!(panicked.is_err());
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn drain_gives_up_on_slow_requests_after_the_timeout() {
+        let shutdown = Shutdown::new();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        thread::scope(|scope| {
+            let in_flight = shutdown.track_request();
+            scope.spawn(move || {
+                let _in_flight = in_flight;
+                let _ = finished.recv_timeout(Duration::from_secs(10));
+            });
+
+            let draining = Instant::now();
+            assert_eq!(shutdown.drain(Duration::from_millis(100)), 1);
+            let waited = draining.elapsed();
+            assert!(waited >= Duration::from_millis(100), "{:?}", waited);
+            assert!(waited < Duration::from_secs(5), "{:?}", waited);
+
+            finish.send(()).unwrap();
+        });
+        assert_eq!(shutdown.drain(Duration::from_millis(100)), 0);
+    }
+
+    #[test]
+    fn shutdown_wakes_connections_idle_between_requests() {
+        let shutdown = Shutdown::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let connection = Connection::Tcp(stream);
+
+        let woken = thread::scope(|scope| {
+            let idle = shutdown
+                .track_idle(&connection)
+                .expect("not shutting down yet");
+            let waiting = scope.spawn(|| {
+                let waiting = Instant::now();
+                let mut byte = [0];
+                let read = (&connection).read(&mut byte);
+                (read.ok(), waiting.elapsed())
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(shutdown.request());
+            let woken = waiting.join().unwrap();
+            drop(idle);
+            woken
+        });
+        assert_eq!(woken.0, Some(0));
+        assert!(woken.1 < Duration::from_secs(5), "{:?}", woken.1);
+        assert!(!shutdown.request());
+        assert!(shutdown.track_idle(&connection).is_none());
+    }
+
+    #[test]
+    fn trickle_fed_requests_time_out_with_408_and_are_disconnected() {
+        let configuration = Configuration {
+            min_read_rate: Some(1000),
+            min_read_rate_window: Duration::from_millis(200),
+            ..Configuration::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            with_configuration(configuration, || {
+                handle_connection(&Connection::Tcp(stream), Instant::now())
+            });
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        for byte in b"GET /".iter() {
+            client.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn bodies_above_the_spill_threshold_go_to_disk() {
+        let configuration = Configuration {
+            spill_threshold_bytes: Some(4),
+            ..Configuration::default()
+        };
+        with_configuration(configuration.clone(), || {
+            let request =
+                parse("POST /post-echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nsmol").unwrap();
+            assert!(matches!(request.body, Some(RequestBody::InMemory(_))));
+
+            let request =
+                parse("POST /post-echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nlarge").unwrap();
+            let Some(RequestBody::Spilled(spilled)) = &request.body else {
+                panic!("body should be spilled: {:?}", request.body);
+            };
+            assert_eq!(spilled.len, 5);
+            assert_eq!(body_of(&request), b"large");
+        });
+
+        let response = exchange_with(
+            configuration,
+            b"POST /post-echo HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nlarge",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nlarge"), "{}", response);
+    }
+
+    #[test]
+    fn echo_revalidates_against_its_weak_etag() {
+        let response = exchange(b"GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .expect("echo should carry an ETag")
+            .to_owned();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+
+        for candidate in [etag.as_str(), etag.trim_start_matches("W/")] {
+            let response = exchange(
+                format!(
+                    "GET /echo/abc HTTP/1.1\r\nIf-None-Match: \"other\", {}\r\nConnection: close\r\n\r\n",
+                    candidate
+                )
+                .as_bytes(),
+            );
+            assert!(
+                response.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+                "{}",
+                response
+            );
+            assert!(response.ends_with("\r\n\r\n"), "{}", response);
+        }
+
+        let response = exchange(
+            b"GET /echo/abd HTTP/1.1\r\nIf-None-Match: W/\"3-0\"\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn echo_applies_the_requested_transform() {
+        for (transform, expected) in [("upper", "ABC"), ("lower", "abc"), ("reverse", "cBa")] {
+            let response = exchange(
+                format!(
+                    "GET /echo/aBc?transform={} HTTP/1.1\r\nConnection: close\r\n\r\n",
+                    transform
+                )
+                .as_bytes(),
+            );
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(
+                response.ends_with(&format!("\r\n\r\n{}", expected)),
+                "{}",
+                response
+            );
+        }
+
+        let response =
+            exchange(b"GET /echo/aBc?transform=rot13 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn echo_trailing_newline_adds_one_byte() {
+        let echo = |echo_trailing_newline| {
+            exchange_with(
+                Configuration {
+                    echo_trailing_newline,
+                    ..Configuration::default()
+                },
+                b"GET /echo/hello HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+        };
+        let plain = echo(false);
+        assert!(plain.contains("\r\nContent-Length: 5\r\n"), "{}", plain);
+        assert!(plain.ends_with("\r\n\r\nhello"), "{}", plain);
+        let terminated = echo(true);
+        assert!(
+            terminated.contains("\r\nContent-Length: 6\r\n"),
+            "{}",
+            terminated
+        );
+        assert!(terminated.ends_with("\r\n\r\nhello\n"), "{}", terminated);
+    }
+
+    #[test]
+    fn only_statuses_that_allow_a_body_frame_an_empty_one() {
+        for (code, status) in [
+            (100, "Continue"),
+            (101, "Switching Protocols"),
+            (204, "No Content"),
+            (304, "Not Modified"),
+        ] {
+            let status_code = StatusCode { code, status };
+            assert_eq!(
+                render(&Response::empty_response(&status_code), true),
+                format!("HTTP/1.1 {} {}\r\n\r\n", code, status)
+            );
+        }
+        assert_eq!(
+            render(&Response::empty_response(&status_codes::NOT_FOUND), true),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            render(&Response::empty_response(&status_codes::OK), true),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn cookies_set_sends_one_set_cookie_per_pair() {
+        let response = exchange(b"GET /cookies/set?a=1&b=2 HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let cookies: Vec<&str> = head_of(&response)
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .collect();
+        assert_eq!(cookies.len(), 2, "{}", response);
+        assert!(
+            cookies.contains(&"a=1") && cookies.contains(&"b=2"),
+            "{:?}",
+            cookies
+        );
+        assert!(response.contains("\r\nCache-Control: no-store\r\n"));
+    }
+
+    #[test]
+    fn write_to_stream_returns_the_bytes_it_wrote() {
+        let dir = temp_dir("written-count");
+        let path = dir.join("file");
+        std::fs::write(&path, b"file contents").unwrap();
+        let responses = [
+            Response::empty_response(&status_codes::NOT_FOUND),
+            Response::text_reponse(&status_codes::OK, "hello").with_header("X-Extra", "1"),
+            Response::file_response(&path),
+        ];
+        for response in &responses {
+            for include_body in [true, false] {
+                let mut written = Vec::new();
+                let count = response
+                    .write_to_stream(&mut written, include_body)
+                    .unwrap();
+                assert_eq!(count, written.len());
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotating_file_moves_full_logs_aside() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("server.log");
+        let mut log = RotatingFile::open(path.clone(), 13, 2).unwrap();
+        log.write_line("first").unwrap();
+        log.write_line("second").unwrap();
+        assert!(!dir.join("server.log.1").exists());
+        log.write_line("third").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("server.log.1")).unwrap(),
+            "first\nsecond\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+
+        log.write_line("fourth").unwrap();
+        log.write_line("fifth").unwrap();
+        log.write_line("sixth").unwrap();
+        log.write_line("seventh").unwrap();
+        // Only `keep` rotations are kept: the oldest, "first" and "second", is gone.
+        assert_eq!(
+            std::fs::read_to_string(dir.join("server.log.2")).unwrap(),
+            "third\nfourth\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("server.log.1")).unwrap(),
+            "fifth\nsixth\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "seventh\n");
+        assert!(!dir.join("server.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_listing_sorts_by_the_requested_key_and_order() {
+        let (configuration, dir) = listing_configuration("listing-sort");
+        std::fs::create_dir_all(dir.join("sorted")).unwrap();
+        for (name, contents) in [("b.txt", "bbb"), ("a.txt", "a"), ("c.txt", "cc")] {
+            std::fs::write(dir.join("sorted").join(name), contents).unwrap();
+        }
+        let listed = |query: &str| {
+            let raw = format!(
+                "GET /files/sorted{} HTTP/1.1\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+                query
+            );
+            let response = exchange_with(configuration.clone(), raw.as_bytes());
+            let mut names: Vec<(usize, &str)> = ["a.txt", "b.txt", "c.txt"]
+                .into_iter()
+                .map(|name| (response.find(name).expect(name), name))
+                .collect();
+            names.sort();
+            names.into_iter().map(|(_, name)| name).collect::<Vec<_>>()
+        };
+        assert_eq!(listed(""), vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(
+            listed("?sort=name&order=asc"),
+            vec!["a.txt", "b.txt", "c.txt"]
+        );
+        assert_eq!(
+            listed("?sort=size&order=desc"),
+            vec!["b.txt", "c.txt", "a.txt"]
+        );
+        assert_eq!(listed("?sort=size"), vec!["a.txt", "c.txt", "b.txt"]);
+
+        let response = exchange_with(
+            configuration,
+            b"GET /files/sorted?order=sideways HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn access_log_sampling_always_keeps_server_errors() {
+        for status in [500, 503, 599] {
+            assert!((0..100).all(|_| logs_access(status, 0.0)));
+        }
+        assert!((0..100).all(|_| !logs_access(200, 0.0)));
+        assert!((0..100).all(|_| logs_access(200, 1.0)));
+        let kept = (0..2000).filter(|_| logs_access(200, 0.5)).count();
+        assert!((700..1300).contains(&kept), "{}", kept);
+    }
+
+    #[test]
+    fn start_line_problems_map_to_distinct_statuses() {
+        let long_target = format!("/{}", "a".repeat(CONFIGURATION.max_uri_bytes));
+        let cases = [
+            (
+                format!("GET {} HTTP/1.1\r\n\r\n", long_target),
+                "414 URI Too Long",
+            ),
+            ("GET /\r\n\r\n".to_owned(), "400 Bad Request"),
+            ("GET / HTTP/1.1 extra\r\n\r\n".to_owned(), "400 Bad Request"),
+            ("GET / HTCPCP/1.0\r\n\r\n".to_owned(), "400 Bad Request"),
+            ("GET / HTTP/one\r\n\r\n".to_owned(), "400 Bad Request"),
+            (
+                "GET / HTTP/2.0\r\n\r\n".to_owned(),
+                "505 HTTP Version Not Supported",
+            ),
+            (
+                "GET / HTTP/3.0\r\n\r\n".to_owned(),
+                "505 HTTP Version Not Supported",
+            ),
+        ];
+        for (raw, status) in cases {
+            let response = exchange(raw.as_bytes());
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{:?}: {}",
+                raw,
+                response
+            );
+            assert!(
+                response.contains("\r\nConnection: close\r\n"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn events_stream_sends_each_event_in_its_own_chunk() {
+        let configuration = Configuration {
+            events_count: 2,
+            ..Configuration::default()
+        };
+        let response = exchange_with(
+            configuration,
+            b"GET /events HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: text/event-stream\r\n"));
+        assert!(response.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let mut chunks = body.split("\r\n");
+        for id in 0..2 {
+            let size = usize::from_str_radix(chunks.next().unwrap(), 16).unwrap();
+            let event = chunks.next().unwrap();
+            assert_eq!(event.len(), size);
+            assert!(
+                event.starts_with(&format!("id: {}\ndata: ", id)),
+                "{:?}",
+                event
+            );
+            assert!(event.ends_with("Z\n\n"), "{:?}", event);
+        }
+        assert_eq!(chunks.collect::<Vec<_>>(), vec!["0", "", ""]);
+    }
+
+    #[test]
+    fn configured_error_page_replaces_a_bodiless_500() {
+        let dir = temp_dir("error-page");
+        let page = dir.join("500.html");
+        std::fs::write(&page, b"<h1>oops</h1>").unwrap();
+        let mapping = format!("500={}", page.display());
+        let configuration = Configuration::from_args(
+            &mut args(&["--error-page", &mapping]),
+            Configuration::default(),
+        );
+        assert_eq!(
+            configuration.error_pages,
+            vec![(500, b"<h1>oops</h1>".to_vec())]
+        );
+
+        let written = with_configuration(configuration, || {
+            let error = std::io::Error::other("disk on fire");
+            render(
+                &Response::internal_error(&error)
+                    .with_error_page()
+                    .with_localized_error_body(Some("fr")),
+                true,
+            )
+        });
+        assert_eq!(
+            written,
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html\r\n\
+             Content-Length: 13\r\n\r\n<h1>oops</h1>"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_limiter_refuses_with_the_same_busy_page() {
+        let busy = |response: Response| {
+            let written = render(&response, true);
+            assert!(
+                written.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: "),
+                "{}",
+                written
+            );
+            assert!(
+                written.ends_with("<p>Server is busy, please retry shortly.</p>\n"),
+                "{}",
+                written
+            );
+        };
+
+        // --max-conn-per-ip and --max-concurrent-requests.
+        let counts = ConnectionCounts::new(Some(1));
+        let ip = Some(std::net::IpAddr::from([127, 0, 0, 1]));
+        let _connection = counts.try_acquire(ip).unwrap();
+        assert!(counts.try_acquire(ip).is_none());
+        let slots = Semaphore::new(Some(1));
+        let _slot = slots.try_acquire().unwrap();
+        assert!(slots.try_acquire().is_none());
+        busy(Response::server_busy());
+
+        // --max-total-body-bytes, refused while parsing.
+        busy(RequestParseError::BodyBudgetExhausted.to_response());
+
+        let configuration = Configuration {
+            retry_after: Duration::from_secs(7),
+            ..Configuration::default()
+        };
+        let written = with_configuration(configuration, || render(&Response::server_busy(), false));
+        assert!(written.contains("\r\nRetry-After: 7\r\n"), "{}", written);
+    }
+
+    #[test]
+    fn forwarded_node_addresses_drop_ports_and_brackets() {
+        assert_eq!(forwarded_node_address("192.0.2.60"), "192.0.2.60");
+        assert_eq!(forwarded_node_address("192.0.2.60:47011"), "192.0.2.60");
+        assert_eq!(forwarded_node_address("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(forwarded_node_address("[2001:db8::1]:4711"), "2001:db8::1");
+        assert_eq!(forwarded_node_address("2001:db8::1"), "2001:db8::1");
+        assert_eq!(forwarded_node_address("unknown"), "unknown");
+    }
+
+    #[test]
+    fn forwarded_client_is_preferred_over_x_forwarded_for() {
+        let client = |headers: &str| {
+            parse(&format!("GET / HTTP/1.1\r\n{}\r\n", headers))
+                .unwrap()
+                .forwarded_client()
+        };
+        assert_eq!(
+            client(
+                "Forwarded: proto=https;For=\"[2001:db8:cafe::17]:4711\", for=198.51.100.1\r\n\
+                 X-Forwarded-For: 203.0.113.9\r\n"
+            )
+            .as_deref(),
+            Some("2001:db8:cafe::17")
+        );
+        assert_eq!(
+            client("Forwarded: for=192.0.2.60:47011;by=203.0.113.43\r\n").as_deref(),
+            Some("192.0.2.60")
+        );
+        // A Forwarded header without a for parameter falls back to X-Forwarded-For.
+        assert_eq!(
+            client("Forwarded: proto=https\r\nX-Forwarded-For: 203.0.113.9, 10.0.0.1\r\n")
+                .as_deref(),
+            Some("203.0.113.9")
+        );
+        assert_eq!(client(""), None);
+    }
+
+    #[test]
+    fn rejects_a_bare_cr_in_the_start_line_and_headers() {
+        for raw in [
+            "GET /a\rb HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Smuggled: a\rTransfer-Encoding: chunked\r\n\r\n",
+            "GET / HTTP/1.1\r\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse(raw), Err(RequestParseError::InvalidStructure)),
+                "{:?}",
+                raw
+            );
+            let response = exchange(raw.as_bytes());
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{:?}: {}",
+                raw,
+                response
+            );
+        }
+        assert!(parse("GET / HTTP/1.1\r\nX-Fine: a b\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn invalid_utf8_in_the_start_line_is_a_bad_request() {
+        let raw = b"GET /caf\xe9 HTTP/1.1\r\n\r\n";
+        assert!(matches!(
+            parse_request(&mut &raw[..], std::io::sink()),
+            Err(RequestParseError::InvalidUtf8)
+        ));
+        let response = exchange(raw);
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn identity_transfer_encoding_reads_the_body_by_content_length() {
+        let request = parse(
+            "POST /post-echo HTTP/1.1\r\nTransfer-Encoding: Identity\r\n\
+             Content-Length: 5\r\n\r\nhello",
+        )
+        .unwrap();
+        assert_eq!(body_of(&request), b"hello");
+
+        let response = exchange(
+            b"POST /post-echo HTTP/1.1\r\nTransfer-Encoding: identity\r\nContent-Length: 5\r\n\r\n\
+              helloGET /echo/next HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nnext"), "{}", response);
+    }
+
+    #[test]
+    fn content_types_with_line_breaks_are_replaced() {
+        let response = Response::text_reponse(&status_codes::OK, "hi")
+            .with_content_type("text/html\r\nSet-Cookie: session=stolen");
+        assert_eq!(
+            render(&response, true),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+             Content-Length: 2\r\n\r\nhi"
+        );
+
+        let (configuration, dir) = files_configuration("ct-injection");
+        std::fs::write(dir.join("page"), b"x").unwrap();
+        let response = exchange_with(
+            configuration,
+            b"GET /files/page?ct=text/html%0D%0ASet-Cookie:%20a=b HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("Set-Cookie"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn http2_preface_gets_505_and_closes() {
+        let preface = "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        assert!(matches!(
+            parse(preface),
+            Err(RequestParseError::Http2Preface)
+        ));
+        let response = exchange(preface.as_bytes());
+        assert!(
+            response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn asterisk_target_is_only_for_options() {
+        for verb in ["GET", "HEAD", "POST"] {
+            let raw = format!("{} * HTTP/1.1\r\n\r\n", verb);
+            assert!(
+                matches!(parse(&raw), Err(RequestParseError::InvalidStructure)),
+                "{}",
+                verb
+            );
+            assert!(exchange(raw.as_bytes()).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        }
+        let response = exchange(b"OPTIONS * HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn root_allows_only_get_head_and_options() {
+        assert_eq!(
+            exchange(b"OPTIONS / HTTP/1.1\r\nConnection: close\r\n\r\n"),
+            "HTTP/1.1 200 OK\r\nAllow: GET, HEAD, OPTIONS\r\nConnection: close\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+        let response =
+            exchange(b"POST / HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn require_length_refuses_unframed_posts_with_411() {
+        let configuration = Configuration {
+            require_length: true,
+            ..Configuration::default()
+        };
+        let response = exchange_with(configuration.clone(), b"POST /post-echo HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 411 Length Required\r\n"),
+            "{}",
+            response
+        );
+        let response = exchange_with(
+            configuration,
+            b"POST /post-echo HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        let response = exchange(b"POST /post-echo HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn connect_is_refused_with_405() {
+        let raw = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        assert!(matches!(
+            parse(raw),
+            Err(RequestParseError::ConnectNotSupported)
+        ));
+        let response = exchange(raw.as_bytes());
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nAllow: "), "{}", response);
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn paths_deeper_than_the_limit_are_refused() {
+        let configuration = Configuration {
+            max_path_depth: 3,
+            ..Configuration::default()
+        };
+        with_configuration(configuration.clone(), || {
+            assert!(parse("GET /echo/a/b HTTP/1.1\r\n\r\n").is_ok());
+            // Empty and `.` segments are collapsed before counting.
+            assert!(parse("GET /echo//a/./b/ HTTP/1.1\r\n\r\n").is_ok());
+            assert!(matches!(
+                parse("GET /echo/a/b/c HTTP/1.1\r\n\r\n"),
+                Err(RequestParseError::PathTooDeep)
+            ));
+        });
+        let response = exchange_with(configuration, b"GET /echo/a/b/c HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn buffer_pool_reuses_returned_buffers_up_to_its_caps() {
+        let pool = BufferPool::new(1, 64);
+        let mut first = pool.take(16);
+        first[0] = 7;
+        let address = first.as_ptr();
+        pool.give_back(first);
+        let second = pool.take(8);
+        assert_eq!(second.as_ptr(), address);
+        assert_eq!(second, vec![0; 8]);
+        assert_eq!(
+            pool.to_json(),
+            "{\"pooled\":0,\"allocated\":1,\"reused\":1}"
+        );
+
+        // Only one buffer is kept, and never one larger than the size cap.
+        pool.give_back(second);
+        pool.give_back(vec![0; 8]);
+        assert_eq!(
+            pool.to_json(),
+            "{\"pooled\":1,\"allocated\":1,\"reused\":1}"
+        );
+        let _pooled = pool.take(0);
+        pool.give_back(vec![0; 65]);
+        assert_eq!(
+            pool.to_json(),
+            "{\"pooled\":0,\"allocated\":1,\"reused\":2}"
+        );
+    }
+
+    #[test]
+    fn not_found_page_is_served_and_falls_back_when_missing() {
+        let dir = temp_dir("not-found-page");
+        let page = dir.join("404.html");
+        std::fs::write(&page, b"<h1>nothing here</h1>").unwrap();
+        let path = page.to_str().unwrap();
+        let configuration = Configuration::from_args(
+            &mut args(&["--not-found-page", path]),
+            Configuration::default(),
+        );
+        let response = exchange_with(
+            configuration,
+            b"GET /nope HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nContent-Type: text/html\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\n<h1>nothing here</h1>"),
+            "{}",
+            response
+        );
+
+        let missing = dir.join("missing.html");
+        let configuration = Configuration::from_args(
+            &mut args(&["--not-found-page", missing.to_str().unwrap()]),
+            Configuration::default(),
+        );
+        assert!(configuration.not_found_page.is_none());
+        let response = exchange_with(
+            configuration,
+            b"GET /nope HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nNot Found"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_mode_refuses_uploads_with_405() {
+        let (configuration, dir) = files_configuration("read-only");
+        let configuration = Configuration {
+            read_only: true,
+            ..configuration
+        };
+        let response = exchange_with(
+            configuration,
+            b"POST /files/x HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            response
+        );
+        assert!(!dir.join("x").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_responses_carry_nosniff_unless_disabled() {
+        let (configuration, dir) = files_configuration("nosniff");
+        std::fs::write(dir.join("upload.html"), b"<script></script>").unwrap();
+        let get = b"GET /files/upload.html HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = exchange_with(configuration.clone(), get);
+        assert!(
+            head_of(&response).contains("\r\nX-Content-Type-Options: nosniff\r\n"),
+            "{}",
+            response
+        );
+        let generated = exchange(b"GET /echo/x HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            !generated.contains("X-Content-Type-Options"),
+            "{}",
+            generated
+        );
+
+        let disabled = Configuration::from_args(&mut args(&["--no-nosniff"]), configuration);
+        let response = exchange_with(disabled, get);
+        assert!(!response.contains("X-Content-Type-Options"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn options_on_unknown_paths_follow_the_configured_policy() {
+        let options = b"OPTIONS /does-not-exist HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = exchange(options);
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("Allow:"), "{}", response);
+
+        let no_content = Configuration::from_args(
+            &mut args(&["--options-unknown", "204"]),
+            Configuration::default(),
+        );
+        assert_eq!(
+            exchange_with(no_content, options),
+            "HTTP/1.1 204 No Content\r\nAllow: \r\nConnection: close\r\n\r\n"
+        );
+
+        let not_found = Configuration::from_args(
+            &mut args(&["--options-unknown", "404"]),
+            Configuration::default(),
+        );
+        let response = exchange_with(not_found, options);
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn every_503_carries_the_configured_retry_after() {
+        let (configuration, dir) = files_configuration("retry-after");
+        let configuration = Configuration {
+            max_request_duration: Some(Duration::ZERO),
+            ..Configuration::from_args(&mut args(&["--retry-after", "12"]), configuration)
+        };
+        let response = exchange_with(
+            configuration.clone(),
+            b"GET /files/anything HTTP/1.1\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\nRetry-After: 12\r\n"),
+            "{}",
+            response
+        );
+
+        let written = with_configuration(configuration, || {
+            [
+                Response::server_busy(),
+                RequestParseError::BodyBudgetExhausted.to_response(),
+            ]
+            .iter()
+            .map(|response| render(response, false))
+            .collect::<Vec<_>>()
+        });
+        for written in written {
+            assert!(written.contains("\r\nRetry-After: 12\r\n"), "{}", written);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deep_health_check_fails_without_a_usable_files_root() {
+        let (configuration, dir) = files_configuration("deep-health");
+        let deep = b"GET /health?deep=1 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = exchange_with(configuration.clone(), deep);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(
+            std::fs::read_dir(&dir).unwrap().count(),
+            0,
+            "probe left behind"
+        );
+
+        // A volume that went away, as an unmounted or deleted root looks.
+        std::fs::remove_dir_all(&dir).unwrap();
+        let response = exchange_with(configuration.clone(), deep);
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        let response = exchange_with(
+            configuration,
+            b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn connection_closes_once_past_its_byte_cap() {
+        let configuration = Configuration {
+            max_conn_bytes: Some(6),
+            ..Configuration::default()
+        };
+        let response = exchange_with(
+            configuration,
+            b"GET /echo/abcd HTTP/1.1\r\n\r\nGET /echo/efgh HTTP/1.1\r\n\r\nGET /echo/ijkl HTTP/1.1\r\n\r\n",
+        );
+        let statuses = response.matches("HTTP/1.1 200 OK\r\n").count();
+        assert_eq!(statuses, 2, "{}", response);
+        // The response that crosses the cap is still sent in full, marked as the last.
+        let last = &response[response.rfind("HTTP/1.1 200 OK").unwrap()..];
+        assert!(last.contains("\r\nConnection: close\r\n"), "{}", last);
+        assert!(last.ends_with("\r\n\r\nefgh"), "{}", last);
+        assert!(!response.contains("ijkl"), "{}", response);
+    }
+
+    #[test]
+    fn repeated_query_keys_are_kept_in_order() {
+        let request = parse("GET /echo/x?tag=a&other=1&tag=b&tag= HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.query_all("tag"), vec!["a", "b", ""]);
+        assert_eq!(request.query("tag"), Some("a"));
+        assert_eq!(request.query_all("other"), vec!["1"]);
+        assert!(request.query_all("missing").is_empty());
+        assert_eq!(request.query("missing"), None);
+    }
+
+    #[test]
+    fn files_options_advertise_writes_only_when_enabled() {
+        let (configuration, dir) = files_configuration("files-options");
+        let options = b"OPTIONS /files/name HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let writable = exchange_with(configuration.clone(), options);
+        assert!(
+            writable.contains("\r\nAllow: GET, HEAD, POST, OPTIONS\r\n"),
+            "{}",
+            writable
+        );
+        let read_only = Configuration {
+            read_only: true,
+            ..configuration
+        };
+        let response = exchange_with(read_only, options);
+        assert!(
+            response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            response
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn post_echo_round_trips_the_body_and_its_content_type() {
+        let response = exchange(
+            b"POST /post-echo HTTP/1.1\r\nContent-Type: application/x-thing\r\n\
+              Content-Length: 6\r\nConnection: close\r\n\r\na\0b\r\nc",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: application/x-thing\r\n"));
+        assert!(
+            response.ends_with("\r\nContent-Length: 6\r\n\r\na\0b\r\nc"),
+            "{:?}",
+            response
+        );
+
+        let response = exchange(
+            b"POST /post-echo HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(
+            response.contains("\r\nContent-Type: application/octet-stream\r\n"),
+            "{}",
+            response
+        );
+
+        let response = exchange(b"GET /post-echo HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn default_headers_apply_unless_the_response_sets_them() {
+        let configuration = Configuration::from_args(
+            &mut args(&[
+                "--default-header",
+                "X-Frame-Options: DENY",
+                "--default-header",
+                "Cache-Control: max-age=60",
+            ]),
+            Configuration::default(),
+        );
+        let written = with_configuration(configuration, || {
+            [
+                Response::text_reponse(&status_codes::OK, "hi"),
+                Response::text_reponse(&status_codes::OK, "hi").no_cache(),
+            ]
+            .iter()
+            .map(|response| render(response, false))
+            .collect::<Vec<_>>()
+        });
+        assert!(
+            written[0].contains("\r\nX-Frame-Options: DENY\r\n"),
+            "{}",
+            written[0]
+        );
+        assert!(
+            written[0].contains("\r\nCache-Control: max-age=60\r\n"),
+            "{}",
+            written[0]
+        );
+        assert!(
+            written[1].contains("\r\nX-Frame-Options: DENY\r\n"),
+            "{}",
+            written[1]
+        );
+        assert!(
+            written[1].contains("\r\nCache-Control: no-store\r\n"),
+            "{}",
+            written[1]
+        );
+        assert!(!written[1].contains("max-age=60"), "{}", written[1]);
+    }
+
+    #[test]
+    fn max_requests_starts_a_shutdown_on_the_last_request() {
+        let handled = AtomicUsize::new(0);
+        let shutdown = Shutdown::new();
+        for _ in 0..2 {
+            if count_request(&handled, Some(3)) {
+                shutdown.request();
+            }
+            assert!(!shutdown.is_requested());
+        }
+        assert!(count_request(&handled, Some(3)));
+        assert!(shutdown.request());
+        assert!(shutdown.is_requested());
+        // Requests already under way when the limit hits still count past it.
+        assert!(count_request(&handled, Some(3)));
+        assert_eq!(handled.load(Ordering::SeqCst), 4);
+
+        let unlimited = AtomicUsize::new(0);
+        assert!((0..100).all(|_| !count_request(&unlimited, None)));
+    }
+
+    #[test]
+    fn matrix_params_are_split_off_only_when_enabled() {
+        let raw = "GET /files/name;version=2;draft HTTP/1.1\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.path, "/files/name;version=2;draft");
+        assert!(request.path_params.is_empty());
+
+        let configuration = Configuration {
+            matrix_params: true,
+            ..Configuration::default()
+        };
+        let request = with_configuration(configuration.clone(), || parse(raw).unwrap());
+        assert_eq!(request.path, "/files/name");
+        assert_eq!(
+            request.path_params,
+            vec![
+                ("version".to_owned(), "2".to_owned()),
+                ("draft".to_owned(), String::new())
+            ]
+        );
+
+        let response = exchange_with(
+            configuration,
+            b"GET /debug;v=1/path-params HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.ends_with("\r\n\r\n{\"v\":\"1\"}"), "{}", response);
+    }
+
+    #[test]
+    fn concurrent_uploads_beyond_the_cap_are_refused() {
+        let uploads = Semaphore::new(Some(2));
+        let started = std::sync::Barrier::new(8);
+        let attempted = std::sync::Barrier::new(8);
+        let granted = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    started.wait();
+                    let permit = uploads.try_acquire();
+                    if permit.is_some() {
+                        granted.fetch_add(1, Ordering::SeqCst);
+                    }
+                    // Every upload holds its slot until all have tried for one.
+                    attempted.wait();
+                });
+            }
+        });
+        assert_eq!(granted.load(Ordering::SeqCst), 2);
+        assert_eq!(uploads.in_use.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn immutable_pattern_marks_only_matching_files() {
+        let (configuration, dir) = files_configuration("immutable");
+        std::fs::write(dir.join("app.3f9a2c.js"), b"js").unwrap();
+        std::fs::write(dir.join("app.js"), b"js").unwrap();
+        let configuration = Configuration {
+            immutable_pattern: Some("*.??????.js".to_owned()),
+            ..configuration
+        };
+        let hashed = exchange_with(
+            configuration.clone(),
+            b"GET /files/app.3f9a2c.js HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            hashed.contains("\r\nCache-Control: public, max-age=31536000, immutable\r\n"),
+            "{}",
+            hashed
+        );
+        let plain = exchange_with(
+            configuration,
+            b"GET /files/app.js HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(plain.starts_with("HTTP/1.1 200 OK\r\n"), "{}", plain);
+        assert!(!plain.contains("Cache-Control"), "{}", plain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metrics_count_request_bodies() {
+        let scrape = || {
+            let response = exchange(b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n");
+            let value = |name: &str| -> usize {
+                response
+                    .lines()
+                    .find_map(|line| line.strip_prefix(&format!("{} ", name)))
+                    .unwrap_or_else(|| panic!("{} missing: {}", name, response))
+                    .parse()
+                    .unwrap()
+            };
+            (
+                value("http_requests_with_body_total"),
+                value("http_request_body_bytes_total"),
+            )
+        };
+        let (bodies, bytes) = scrape();
+        exchange(b"POST /post-echo HTTP/1.1\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world");
+        let (bodies_after, bytes_after) = scrape();
+        // Other tests may post bodies in parallel, so only a lower bound holds.
+        assert!(bodies_after > bodies);
+        assert!(bytes_after >= bytes + 11);
+    }
+
+    #[test]
+    fn files_stream_intact_with_tiny_and_large_chunks() {
+        let (configuration, dir) = files_configuration("stream-chunks");
+        let contents: Vec<u8> = (0..10_000u32).map(|n| (n % 251) as u8).collect();
+        std::fs::write(dir.join("data"), &contents).unwrap();
+        for stream_chunk_bytes in [1, 7, 4096, 1 << 20] {
+            let configuration = Configuration {
+                stream_chunk_bytes,
+                ..configuration.clone()
+            };
+            let mut written = Vec::new();
+            with_configuration(configuration, || {
+                Response::file_response(&dir.join("data"))
+                    .write_to_stream(&mut written, true)
+                    .unwrap()
+            });
+            let body = &written[written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4..];
+            assert!(
+                body == contents.as_slice(),
+                "chunk size {}",
+                stream_chunk_bytes
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn time_is_a_well_formed_utc_timestamp_in_text_or_json() {
+        let well_formed = |timestamp: &str| {
+            let bytes = timestamp.as_bytes();
+            bytes.len() == 20
+                && bytes.iter().enumerate().all(|(i, byte)| match i {
+                    4 | 7 => *byte == b'-',
+                    10 => *byte == b'T',
+                    13 | 16 => *byte == b':',
+                    19 => *byte == b'Z',
+                    _ => byte.is_ascii_digit(),
+                })
+        };
+        assert!(well_formed(&iso8601_utc(0)));
+        assert_eq!(iso8601_utc(951_782_400), "2000-02-29T00:00:00Z");
+
+        let text = exchange(b"GET /time HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            text.contains("\r\nContent-Type: text/plain\r\n"),
+            "{}",
+            text
+        );
+        let timestamp = &text[text.find("\r\n\r\n").unwrap() + 4..];
+        assert!(well_formed(timestamp), "{:?}", timestamp);
+        assert!(timestamp >= "2024-", "{:?}", timestamp);
+
+        let json = exchange(
+            b"GET /time HTTP/1.1\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            json.contains("\r\nContent-Type: application/json\r\n"),
+            "{}",
+            json
+        );
+        let body = &json[json.find("\r\n\r\n").unwrap() + 4..];
+        let timestamp = body
+            .strip_prefix("{\"utc\":\"")
+            .and_then(|rest| rest.strip_suffix("\"}"))
+            .unwrap_or_else(|| panic!("{}", body));
+        assert!(well_formed(timestamp), "{:?}", timestamp);
+    }
+
+    #[test]
+    fn last_allowed_request_carries_connection_close() {
+        let configuration = Configuration {
+            max_conn_requests: Some(2),
+            ..Configuration::default()
+        };
+        let response = exchange_with(
+            configuration,
+            b"GET /echo/one HTTP/1.1\r\n\r\nGET /echo/two HTTP/1.1\r\n\r\nGET /echo/three HTTP/1.1\r\n\r\n",
+        );
+        let responses: Vec<&str> = response
+            .split("HTTP/1.1 ")
+            .filter(|part| !part.is_empty())
+            .collect();
+        assert_eq!(responses.len(), 2, "{}", response);
+        assert!(!responses[0].contains("Connection:"), "{}", responses[0]);
+        assert!(responses[0].ends_with("one"));
+        assert!(
+            responses[1].contains("\r\nConnection: close\r\n"),
+            "{}",
+            responses[1]
+        );
+        assert!(responses[1].ends_with("two"));
+    }
+
+    #[test]
+    fn handlers_can_force_the_connection_closed() {
+        let (configuration, dir) = files_configuration("force-close");
+        // A request already past its deadline is answered and its connection given up.
+        let configuration = Configuration {
+            max_request_duration: Some(Duration::ZERO),
+            ..configuration
+        };
+        let response = exchange_with(
+            configuration,
+            b"GET /files/x HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /echo/next HTTP/1.1\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("next"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn robots_txt_is_served_from_a_file_or_disallows_all() {
+        let get = b"GET /robots.txt HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let response = exchange(get);
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+
+        let dir = temp_dir("robots");
+        let path = dir.join("robots.txt");
+        std::fs::write(&path, b"User-agent: *\nDisallow: /private\n").unwrap();
+        let from_file = Configuration::from_args(
+            &mut args(&["--robots", path.to_str().unwrap()]),
+            Configuration::default(),
+        );
+        let response = exchange_with(from_file, get);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains("\r\nContent-Type: text/plain\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("\r\n\r\nUser-agent: *\nDisallow: /private\n"),
+            "{}",
+            response
+        );
+
+        let disallow_all = Configuration::from_args(
+            &mut args(&["--robots-disallow-all"]),
+            Configuration::default(),
+        );
+        let response = exchange_with(disallow_all, get);
+        assert!(
+            response.ends_with("\r\n\r\nUser-agent: *\nDisallow: /\n"),
+            "{}",
+            response
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_directory_listings_exceed_the_generated_cap() {
+        let (configuration, dir) = listing_configuration("listing-cap");
+        std::fs::create_dir_all(dir.join("big")).unwrap();
+        for n in 0..200 {
+            std::fs::write(dir.join("big").join(format!("file-{:03}.txt", n)), b"").unwrap();
+        }
+        let get = b"GET /files/big HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let uncapped = exchange_with(configuration.clone(), get);
+        assert!(uncapped.starts_with("HTTP/1.1 200 OK\r\n"), "{}", uncapped);
+        assert!(uncapped.contains("file-199.txt"));
+
+        let capped = Configuration {
+            max_generated_bytes: Some(4096),
+            ..configuration
+        };
+        let response = exchange_with(capped, get);
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            response
+        );
+        assert!(!response.contains("file-"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn uploads_get_the_configured_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let (configuration, dir) = files_configuration("upload-mode");
+        let configuration =
+            Configuration::from_args(&mut args(&["--upload-mode", "0640"]), configuration);
+        assert_eq!(configuration.upload_mode, Some(0o640));
+        let response = exchange_with(
+            configuration,
+            b"POST /files/secret HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{}",
+            response
+        );
+        let mode = std::fs::metadata(dir.join("secret"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn templates_substitute_query_values() {
+        let dir = temp_dir("templates");
+        std::fs::write(
+            dir.join("greeting"),
+            "Hello, {{name}}!{{ missing }} {{unclosed",
+        )
+        .unwrap();
+        let configuration = Configuration {
+            template_root: Some(dir.to_str().unwrap().to_owned()),
+            ..Configuration::default()
+        };
+        let response = exchange_with(
+            configuration.clone(),
+            b"GET /template/greeting?name=World HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.ends_with("\r\n\r\nHello, World! {{unclosed"),
+            "{}",
+            response
+        );
+
+        let response = exchange_with(
+            configuration,
+            b"GET /template/../greeting HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(!response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_demo_routes_are_not_found() {
+        let disabled = Configuration::from_args(
+            &mut args(&["--disable-demo-routes"]),
+            Configuration::default(),
+        );
+        for raw in [
+            &b"GET /echo/x HTTP/1.1\r\nConnection: close\r\n\r\n"[..],
+            b"GET /user-agent HTTP/1.1\r\nUser-Agent: probe\r\nConnection: close\r\n\r\n",
+            b"OPTIONS /echo/x HTTP/1.1\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = exchange_with(disabled.clone(), raw);
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{}",
+                response
+            );
+        }
+        let response = exchange_with(
+            disabled,
+            b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn no_expect_continue_reads_the_body_without_an_interim_response() {
+        let configuration = Configuration::from_args(
+            &mut args(&["--no-expect-continue"]),
+            Configuration::default(),
+        );
+        let raw = "POST /post-echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi";
+        let mut interim = Vec::new();
+        let request = with_configuration(configuration.clone(), || {
+            parse_request(&mut raw.as_bytes(), &mut interim).unwrap()
+        });
+        assert!(interim.is_empty(), "{:?}", interim);
+        assert_eq!(body_of(&request), b"hi");
+
+        let response = exchange_with(configuration, raw.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(!response.contains("100 Continue"), "{}", response);
+    }
+
+    #[test]
+    fn per_ip_connection_limit_counts_each_address_separately() {
+        let counts = ConnectionCounts::new(Some(2));
+        let client = Some(std::net::IpAddr::from([192, 0, 2, 1]));
+        let other = Some(std::net::IpAddr::from([192, 0, 2, 2]));
+        let first = counts.try_acquire(client).unwrap();
+        let second = counts.try_acquire(client).unwrap();
+        assert!(counts.try_acquire(client).is_none());
+        assert!(counts.try_acquire(other).is_some());
+        // Unix socket peers have no address and are never limited.
+        assert!((0..5).all(|_| counts.try_acquire(None).is_some()));
+
+        drop(first);
+        let third = counts.try_acquire(client);
+        assert!(third.is_some());
+        drop(third);
+        drop(second);
+        assert!(counts.counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn children_of_regular_files_are_not_found() {
+        let (configuration, dir) = files_configuration("through-file");
+        std::fs::write(dir.join("a.txt"), b"a").unwrap();
+        for raw in [
+            &b"GET /files/a.txt/b HTTP/1.1\r\nConnection: close\r\n\r\n"[..],
+            b"GET /files/a.txt/b/c HTTP/1.1\r\nConnection: close\r\n\r\n",
+            b"POST /files/a.txt/b HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx",
+        ] {
+            let response = exchange_with(configuration.clone(), raw);
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{}",
+                response
+            );
+        }
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"a");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accept_errors_back_off_up_to_the_cap_and_reset_on_success() {
+        let failing =
+            |count| (0..count).map(|_| Err(std::io::Error::from(std::io::ErrorKind::Other)));
+        let timed = |configuration: Configuration, incoming: Vec<std::io::Result<Connection>>| {
+            with_configuration(configuration, || {
+                let started = Instant::now();
+                accept_loop(incoming.into_iter());
+                started.elapsed()
+            })
+        };
+
+        // 5 + 10 + 20 * 6 ms when capped at 20ms; over a second if the cap were ignored.
+        let capped = Configuration {
+            accept_backoff_max: Duration::from_millis(20),
+            ..Configuration::default()
+        };
+        let waited = timed(capped, failing(8).collect());
+        assert!(waited >= Duration::from_millis(135), "{:?}", waited);
+        assert!(waited < Duration::from_millis(1000), "{:?}", waited);
+
+        // 5 + 10 + 20 + 40 + 80 + 160 ms, then a success, then 5 ms rather than 320 ms.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        drop(client);
+        let incoming: Vec<_> = failing(6)
+            .chain(std::iter::once(Ok(Connection::Tcp(stream))))
+            .chain(failing(1))
+            .collect();
+        let waited = timed(Configuration::default(), incoming);
+        assert!(waited >= Duration::from_millis(320), "{:?}", waited);
+        assert!(waited < Duration::from_millis(550), "{:?}", waited);
+    }
+
+    #[test]
+    fn no_cache_responses_send_no_store_and_pragma() {
+        assert_eq!(
+            render(
+                &Response::empty_response(&status_codes::OK).no_cache(),
+                true
+            ),
+            "HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nPragma: no-cache\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+        let response = exchange(b"GET /debug/requests HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            response.contains("\r\nCache-Control: no-store\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nPragma: no-cache\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn paths_requiring_an_upgrade_get_426_with_upgrade() {
+        let configuration = Configuration::from_args(
+            &mut args(&["--require-upgrade", "/secure/*=TLS/1.2, HTTP/1.1"]),
+            Configuration::default(),
+        );
+        let response = exchange_with(configuration.clone(), b"GET /secure/page HTTP/1.1\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 426 Upgrade Required\r\nUpgrade: TLS/1.2, HTTP/1.1\r\n"),
+            "{}",
+            response
+        );
+        let response = exchange_with(
+            configuration,
+            b"GET /echo/open HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn accept_to_first_byte_latency_is_recorded() {
+        let histogram = Histogram::new(&[0.01, 0.1]);
+        histogram.observe(Duration::from_millis(50));
+        assert_eq!(
+            histogram.to_prometheus("latency"),
+            "# TYPE latency histogram\nlatency_bucket{le=\"0.01\"} 0\nlatency_bucket{le=\"0.1\"} 1\n\
+             latency_bucket{le=\"+Inf\"} 1\nlatency_sum 0.05\nlatency_count 1\n"
+        );
+
+        let recorded = || {
+            (
+                FIRST_BYTE_LATENCY.count.load(Ordering::SeqCst),
+                FIRST_BYTE_LATENCY.sum_micros.load(Ordering::SeqCst),
+            )
+        };
+        let (count, sum) = recorded();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let accepted = Instant::now();
+        thread::sleep(Duration::from_millis(50));
+        client
+            .write_all(b"GET /echo/x HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        handle_connection(&Connection::Tcp(stream), accepted);
+        let (count_after, sum_after) = recorded();
+        assert!(count_after > count);
+        assert!(sum_after - sum >= 50_000, "{} -> {}", sum, sum_after);
+
+        let metrics = exchange(b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(
+            metrics.contains("\n# TYPE http_accept_to_first_byte_seconds histogram\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn force_connection_overrides_what_the_client_asked_for() {
+        let forced = |value: &str| {
+            Configuration::from_args(
+                &mut args(&["--force-connection", value]),
+                Configuration::default(),
+            )
+        };
+
+        // Forced close ends even a keep-alive HTTP/1.1 connection after the first response.
+        let response = exchange_with(
+            forced("close"),
+            b"GET /echo/one HTTP/1.1\r\nConnection: keep-alive\r\n\r\nGET /echo/two HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            1,
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nConnection: close\r\n"),
+            "{}",
+            response
+        );
+
+        // Forced keep-alive holds even for an HTTP/1.0 client that did not ask for it, so the
+        // connection stays open for the next request.
+        let response = exchange_with(
+            forced("keep-alive"),
+            b"GET /echo/one HTTP/1.0\r\n\r\nGET /echo/two HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            2,
+            "{}",
+            response
+        );
+        let first = &response[..response.rfind("HTTP/1.1 200 OK").unwrap()];
+        assert!(
+            first.contains("\r\nConnection: keep-alive\r\n"),
+            "{}",
+            first
+        );
     }
 }